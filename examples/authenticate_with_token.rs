@@ -1,6 +1,6 @@
 extern crate dementia;
 
-use dementia::{Homeserver, Message, RoomEvent};
+use dementia::{Homeserver, InvitePolicy, Message, RoomEvent, RoomOrAliasId};
 use std::{thread, time};
 
 fn main() {
@@ -17,7 +17,15 @@ fn main() {
 
     let mut server = Homeserver::new(&argv[1]).access_token(&argv[2]).connect();
 
-    let mut room = match server.join_room(argv[3].clone()) {
+    let room_id: RoomOrAliasId = match argv[3].parse() {
+        Ok(room_id) => room_id,
+        Err(error) => {
+            println!("{}", error);
+            return;
+        }
+    };
+
+    let room = match server.join_room(room_id) {
         Some(r) => r,
         _ => {
             println!("Joining room '{}' failed!", argv[3]);
@@ -25,10 +33,28 @@ fn main() {
         }
     };
 
+    let policy = match server.whoami() {
+        Some(info) => InvitePolicy::new().allow_server(&info.user_id.server_name()),
+        None => {
+            println!("Looking up the user ID failed!");
+            return;
+        }
+    };
+
     loop {
-        // Follow all invites
-        for invite in server.get_invites() {
-            server.join_room(invite);
+        // Follow invites of users on the bot's homeserver
+        match server.accept_invites(&policy) {
+            Ok(outcomes) => {
+                for (invite, outcome) in outcomes {
+                    if let Err(error) = outcome {
+                        println!(
+                            "Handling the invite to {} failed: {}",
+                            invite.room_id, error
+                        );
+                    }
+                }
+            }
+            Err(error) => println!("Fetching the invites failed: {}", error),
         }
 
         // Reply to message "hi" in room given as parameter
@@ -37,7 +63,9 @@ fn main() {
                 RoomEvent::Message(Message::Text(text)) => {
                     println!("{}", text);
                     if text == "hi" {
-                        room.send_notice("ahoi!".to_owned());
+                        if let Err(error) = room.send_notice("ahoi!".to_owned()) {
+                            println!("Sending the reply failed: {}", error);
+                        }
                     }
                 }
                 _ => (),
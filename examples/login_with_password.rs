@@ -1,6 +1,6 @@
 extern crate dementia;
 
-use dementia::{Homeserver, Message, RoomEvent};
+use dementia::{Homeserver, InvitePolicy, Message, RoomEvent, RoomOrAliasId};
 use std::{thread, time};
 
 fn main() {
@@ -15,13 +15,27 @@ fn main() {
         return;
     }
 
-    let mut server = Homeserver::new(&argv[1])
+    let server = match Homeserver::new(&argv[1])
         .username(&argv[2])
         .password(&argv[3])
         .login()
-        .connect();
+    {
+        Ok(builder) => builder.connect(),
+        Err(error) => {
+            println!("Login failed: {}", error);
+            return;
+        }
+    };
+
+    let room_id: RoomOrAliasId = match argv[4].parse() {
+        Ok(room_id) => room_id,
+        Err(error) => {
+            println!("{}", error);
+            return;
+        }
+    };
 
-    let mut room = match server.join_room(argv[4].clone()) {
+    let room = match server.join_room(room_id) {
         Some(r) => r,
         _ => {
             println!("Joining room '{}' failed!", argv[4]);
@@ -29,10 +43,25 @@ fn main() {
         }
     };
 
+    let policy = match server.get_user_id() {
+        Some(user_id) => InvitePolicy::new().allow_server(&user_id.server_name()),
+        None => InvitePolicy::new(),
+    };
+
     loop {
-        // Follow all invites
-        for invite in server.get_invites() {
-            server.join_room(invite);
+        // Follow invites of users on the bot's homeserver
+        match server.accept_invites(&policy) {
+            Ok(outcomes) => {
+                for (invite, outcome) in outcomes {
+                    if let Err(error) = outcome {
+                        println!(
+                            "Handling the invite to {} failed: {}",
+                            invite.room_id, error
+                        );
+                    }
+                }
+            }
+            Err(error) => println!("Fetching the invites failed: {}", error),
         }
 
         // Reply to message "hi" in room given as parameter
@@ -41,7 +70,9 @@ fn main() {
                 RoomEvent::Message(Message::Text(text)) => {
                     println!("{}", text);
                     if text == "hi" {
-                        room.send_notice("ahoi!".to_owned());
+                        if let Err(error) = room.send_notice("ahoi!".to_owned()) {
+                            println!("Sending the reply failed: {}", error);
+                        }
                     }
                 }
                 _ => (),
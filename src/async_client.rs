@@ -0,0 +1,600 @@
+//! Non-blocking variants of `Homeserver` and `Room`
+//!
+//! The types are only public with the cargo feature `async`. `Homeserver` and
+//! `Room` are wrappers around them, see `reactor`.
+//! All requests return futures which have to be run on a `tokio_core`
+//! reactor. This way, a single thread can drive many rooms and accounts
+//! concurrently.
+//!
+//! Discovery, logging in and registering have non-blocking variants on
+//! `Homeserver` and the `HomeserverBuilder` ending in `_async`, e. g.
+//! `login_async`. The builder is then connected with `connect_async`.
+//! Only a few methods have no such variant:
+//!
+//! * `HomeserverBuilder::login_sso` waits for the user to log in in a
+//!   browser, use `sso_redirect_url` and `login_token_async` instead.
+//! * The shortcuts `Homeserver::connect`, `login_and_connect`,
+//!   `register_and_connect` and `register_guest` return a blocking
+//!   homeserver object, use the builder methods instead.
+//! * `HomeserverBuilder::login_flows` and `supports_login` have the variants
+//!   `login_flows_async` and `supports_login_async`.
+//!
+//! Accessing the state store may block, e. g. for a `FileStore`, so the
+//! outbox methods of `AsyncRoom` and logging out access it on a separate
+//! thread.
+//!
+//! # Examples
+//!
+//! ```
+//! let mut core = Core::new()?;
+//! let server = core
+//!     .run(
+//!         Homeserver::new("https://matrix.org")
+//!             .username("bot")
+//!             .password("secret")
+//!             .login_async(&core.handle()),
+//!     )?
+//!     .connect_async(&core.handle());
+//! let alias: RoomAliasId = "#bottest:matrix.org".parse()?;
+//! let room = core.run(server.join_room(alias))?;
+//! core.run(room.send_notice("Hallo".to_owned()))?;
+//! ```
+
+// Without the feature, only the methods the blocking API wraps are used
+#![cfg_attr(not(feature = "async"), allow(dead_code))]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, Loop, Shared};
+use futures::sync::oneshot;
+use futures::Future;
+use reqwest::unstable::async;
+use serde::de::DeserializeOwned;
+use tokio_core::reactor::Handle;
+
+use invite::InviteAction;
+use outbox::{self, OutboxEntry};
+use reactor::run_blocking;
+use request::Call;
+use {
+    aliases_call, canonical_alias_call, capabilities_call, create_alias_call, create_room_call,
+    delete_alias_call, event_permalink, invites_call, join_call, logout_call, member_call,
+    members_call, membership_call, new_events_call, public_room_options, resolve_alias_call,
+    send_message_call, set_canonical_alias_call, timeline_end_call, versions_call, whoami_call,
+    CanonicalAlias, Capabilities, CreateRoomBuilder, Error, EventId, Invite, InviteOutcomes,
+    InvitePolicy, LoginInfo, Member, Membership, Message, Permalink, Room, RoomAliasId,
+    RoomAliasInfo, RoomEvent, RoomId, RoomOrAliasId, ServerInfo, Session, UserId, Versions, WhoAmI,
+};
+#[cfg(feature = "async")]
+use {Homeserver, HomeserverBuilder};
+
+/// The result of a non-blocking operation
+pub type AsyncResult<T> = Box<dyn Future<Item = T, Error = Error>>;
+
+/// Returns a future that fails right away
+pub(crate) fn fail<T: 'static, E: Into<Error>>(error: E) -> AsyncResult<T> {
+    Box::new(future::err(error.into()))
+}
+
+/// Runs non-blocking operations one after another
+///
+/// An operation is only started once all operations started before it have
+/// finished. Unlike a `RefCell`, the lock can be shared between threads,
+/// like the `ServerInfo` holding it.
+#[derive(Clone, Default)]
+pub(crate) struct AsyncLock(Arc<Mutex<Option<Finished>>>);
+
+/// Resolves once an operation and all operations before it have finished
+type Finished = Shared<Box<dyn Future<Item = (), Error = ()> + Send>>;
+
+impl AsyncLock {
+    /// Runs `operation` once the previous operation has finished
+    ///
+    /// If the returned future is dropped, the operation counts as finished
+    /// as soon as the previous one has.
+    pub fn run<T, F>(&self, operation: F) -> AsyncResult<T>
+    where
+        T: 'static,
+        F: FnOnce() -> AsyncResult<T> + 'static,
+    {
+        let (done, finished) = oneshot::channel();
+        let previous = {
+            let mut last = self.0.lock().unwrap();
+            let previous: Box<dyn Future<Item = (), Error = ()> + Send> = match last.take() {
+                Some(previous) => Box::new(previous.then(|_| Ok(()))),
+                None => Box::new(future::ok(())),
+            };
+            let previous = previous.shared();
+            let finished: Box<dyn Future<Item = (), Error = ()> + Send> =
+                Box::new(previous.clone().then(|_| finished.then(|_| Ok(()))));
+            *last = Some(finished.shared());
+            previous
+        };
+        Box::new(previous.then(move |_| operation()).then(move |res| {
+            let _ = done.send(());
+            res
+        }))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, R> HomeserverBuilder<T, R, String> {
+    /// Create a non-blocking homeserver object, see `AsyncHomeserver`
+    ///
+    /// All requests of the homeserver object and its rooms run on the
+    /// reactor of `handle`. The HTTP client is built with the options of the
+    /// builder.
+    pub fn connect_async(self, handle: &Handle) -> AsyncHomeserver {
+        let client = self.http.async_client(handle);
+        let (info, login_info) = self.into_server_info();
+        AsyncHomeserver::new(&client, handle, info, login_info)
+    }
+}
+
+#[cfg(feature = "async")]
+impl Homeserver {
+    /// Turns this homeserver object into a non-blocking one
+    ///
+    /// The session, and thus a refreshed access token, is shared with the
+    /// rooms created from this homeserver object. The HTTP client is built
+    /// with the options of the builder, see `connect_async`.
+    pub fn into_async(self, handle: &Handle) -> AsyncHomeserver {
+        let client = self.http.async_client(handle);
+        AsyncHomeserver::new(&client, handle, self.info, self.login_info)
+    }
+}
+
+/// Non-blocking variant of `Homeserver`
+///
+/// The methods behave like the ones of `Homeserver`, but return futures.
+/// Failures are reported as `Error` instead of `None` or empty lists.
+#[derive(Clone)]
+pub struct AsyncHomeserver {
+    client: async::Client,
+    handle: Handle,
+    info: ServerInfo,
+    login_info: Option<LoginInfo>,
+}
+
+impl AsyncHomeserver {
+    pub(crate) fn new(
+        client: &async::Client,
+        handle: &Handle,
+        info: ServerInfo,
+        login_info: Option<LoginInfo>,
+    ) -> Self {
+        AsyncHomeserver {
+            client: client.clone(),
+            handle: handle.clone(),
+            info,
+            login_info,
+        }
+    }
+
+    /// Returns the access token, see `Homeserver::get_access_token`
+    pub fn get_access_token(&self) -> String {
+        self.info.session.lock().unwrap().access_token.clone()
+    }
+
+    /// Returns the current session, see `Homeserver::get_session`
+    pub fn get_session(&self) -> Session {
+        self.info.session.lock().unwrap().clone()
+    }
+
+    /// Returns the fully qualified user ID of the logged in user, see
+    /// `Homeserver::get_user_id`
+    pub fn get_user_id(&self) -> Option<UserId> {
+        self.info.session.lock().unwrap().user_id.clone()
+    }
+
+    /// Returns the device ID of the access token, see
+    /// `Homeserver::get_device_id`
+    pub fn get_device_id(&self) -> Option<String> {
+        self.info.session.lock().unwrap().device_id.clone()
+    }
+
+    /// Returns the complete login response, see `Homeserver::get_login_info`
+    pub fn get_login_info(&self) -> Option<LoginInfo> {
+        self.login_info.clone()
+    }
+
+    fn call<R, T>(&self, call: Call<R, T>) -> AsyncResult<T>
+    where
+        R: DeserializeOwned + 'static,
+        T: 'static,
+    {
+        self.info.call(&self.client, &self.handle, call)
+    }
+
+    fn room(&self, room_id: RoomId, latest_since: Option<String>, peeking: bool) -> AsyncRoom {
+        let state = RoomState {
+            id: room_id,
+            latest_since,
+            peeking,
+        };
+        AsyncRoom::new(state, &self.client, &self.handle, self.info.clone())
+    }
+
+    /// Asks the homeserver who the owner of the access token is, see
+    /// `Homeserver::whoami`
+    pub fn whoami(&self) -> AsyncResult<WhoAmI> {
+        self.call(whoami_call(&self.info))
+    }
+
+    /// Asks the homeserver what the user is allowed to do, see
+    /// `Homeserver::capabilities`
+    pub fn capabilities(&self) -> AsyncResult<Capabilities> {
+        self.call(capabilities_call())
+    }
+
+    /// Get the versions of the client-server API and the experimental
+    /// features the homeserver supports
+    pub fn versions(&self) -> AsyncResult<Versions> {
+        self.call(versions_call())
+    }
+
+    /// Invalidates the access token, see `Homeserver::logout`
+    pub fn logout(self) -> AsyncResult<()> {
+        self.logout_with(false)
+    }
+
+    /// Invalidates all access tokens of the user, see `Homeserver::logout_all`
+    pub fn logout_all(self) -> AsyncResult<()> {
+        self.logout_with(true)
+    }
+
+    /// Logs out and clears the state store on its own thread
+    fn logout_with(self, all: bool) -> AsyncResult<()> {
+        let store = self.info.store.clone();
+        Box::new(
+            self.call(logout_call(all))
+                .and_then(move |()| run_blocking(move || Ok(store.clear()?))),
+        )
+    }
+
+    /// Joins a room and creates a room object, see `Homeserver::join_room`
+    pub fn join_room<R: Into<RoomOrAliasId>>(&self, room: R) -> AsyncResult<AsyncRoom> {
+        let server = self.clone();
+        Box::new(
+            self.call(join_call(&room.into()))
+                .map(move |room_id| server.room(room_id, None, false)),
+        )
+    }
+
+    /// Creates a room object for a room without joining it, see
+    /// `Homeserver::peek_room`
+    pub fn peek_room<R: Into<RoomOrAliasId>>(&self, room: R) -> AsyncResult<AsyncRoom> {
+        let room_id: AsyncResult<RoomId> = match room.into() {
+            RoomOrAliasId::Id(room_id) => Box::new(future::ok(room_id)),
+            RoomOrAliasId::Alias(alias) => Box::new(
+                self.resolve_alias(&alias)
+                    .map(|info: RoomAliasInfo| info.room_id),
+            ),
+        };
+
+        let server = self.clone();
+        Box::new(room_id.and_then(move |room_id| {
+            server
+                .call(timeline_end_call(&room_id))
+                .map(move |start| server.room(room_id, start, true))
+        }))
+    }
+
+    /// Creates a new room, see `Homeserver::create_room`
+    ///
+    /// Unlike `Homeserver::create_room`, failures are reported as `Error`.
+    pub fn create_room(&self, room_name: String) -> AsyncResult<AsyncRoom> {
+        self.create_room_with(public_room_options(&room_name))
+    }
+
+    /// Creates a new room with the given options, see
+    /// `Homeserver::create_room_with`
+    pub fn create_room_with(&self, options: CreateRoomBuilder) -> AsyncResult<AsyncRoom> {
+        let server = self.clone();
+        Box::new(
+            self.call(create_room_call(&options))
+                .map(move |room_id| server.room(room_id, None, false)),
+        )
+    }
+
+    /// Get all current invites from the server, see `Homeserver::get_invites`
+    pub fn get_invites(&self) -> AsyncResult<Vec<Invite>> {
+        self.call(invites_call(self.get_user_id()))
+    }
+
+    /// Declines the invite to a room, see `Homeserver::decline_invite`
+    pub fn decline_invite(&self, room_id: &RoomId) -> AsyncResult<()> {
+        self.call(membership_call(room_id, "leave", None, None))
+    }
+
+    /// Joins all rooms whose invite is accepted by the policy, see
+    /// `Homeserver::accept_invites`
+    pub fn accept_invites(&self, policy: &InvitePolicy) -> AsyncResult<InviteOutcomes<AsyncRoom>> {
+        let server = self.clone();
+        let policy = policy.clone();
+        Box::new(self.get_invites().and_then(move |invites| {
+            let handled = invites.into_iter().map(move |invite| {
+                let outcome: AsyncResult<Option<AsyncRoom>> = match policy.action(&invite) {
+                    InviteAction::Accept => {
+                        Box::new(server.join_room(invite.room_id.clone()).map(Some))
+                    }
+                    InviteAction::Decline => {
+                        Box::new(server.decline_invite(&invite.room_id).map(|()| None))
+                    }
+                    InviteAction::Keep => Box::new(future::ok(None)),
+                };
+                outcome.then(move |outcome| Ok((invite, outcome)))
+            });
+            future::join_all(handled)
+        }))
+    }
+
+    /// Looks up the room a room alias points to, see
+    /// `Homeserver::resolve_alias`
+    pub fn resolve_alias(&self, alias: &RoomAliasId) -> AsyncResult<RoomAliasInfo> {
+        self.call(resolve_alias_call(alias))
+    }
+
+    /// Creates a room alias pointing to the given room, see
+    /// `Homeserver::create_alias`
+    pub fn create_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> AsyncResult<()> {
+        self.call(create_alias_call(alias, room_id))
+    }
+
+    /// Deletes a room alias, see `Homeserver::delete_alias`
+    pub fn delete_alias(&self, alias: &RoomAliasId) -> AsyncResult<()> {
+        self.call(delete_alias_call(alias))
+    }
+}
+
+/// What is needed to create a room object again, e. g. to pass it between
+/// `Room` and `AsyncRoom`
+pub(crate) struct RoomState {
+    pub id: RoomId,
+    pub latest_since: Option<String>,
+    /// Whether the room is read without having joined it
+    pub peeking: bool,
+}
+
+/// Non-blocking variant of `Room`
+///
+/// The methods behave like the ones of `Room`, but return futures.
+/// Clones of a room object share the sync token, so new events are only
+/// returned once.
+#[derive(Clone)]
+pub struct AsyncRoom {
+    id: RoomId,
+    latest_since: Rc<RefCell<Option<String>>>,
+    /// Held by `get_new_messages` until the new sync token is stored
+    sync_lock: AsyncLock,
+    peeking: bool,
+    client: async::Client,
+    handle: Handle,
+    info: ServerInfo,
+}
+
+impl AsyncRoom {
+    pub(crate) fn new(
+        state: RoomState,
+        client: &async::Client,
+        handle: &Handle,
+        info: ServerInfo,
+    ) -> Self {
+        AsyncRoom {
+            id: state.id,
+            latest_since: Rc::new(RefCell::new(state.latest_since)),
+            sync_lock: AsyncLock::default(),
+            peeking: state.peeking,
+            client: client.clone(),
+            handle: handle.clone(),
+            info,
+        }
+    }
+
+    pub(crate) fn state(&self) -> RoomState {
+        RoomState {
+            id: self.id.clone(),
+            latest_since: self.latest_since.borrow().clone(),
+            peeking: self.peeking,
+        }
+    }
+
+    fn call<R, T>(&self, call: Call<R, T>) -> AsyncResult<T>
+    where
+        R: DeserializeOwned + 'static,
+        T: 'static,
+    {
+        self.info.call(&self.client, &self.handle, call)
+    }
+
+    /// Returns the ID of the room
+    pub fn id(&self) -> &RoomId {
+        &self.id
+    }
+
+    /// Receive all new events in a room since the last time this function has
+    /// been called, see `Room::get_new_messages`
+    ///
+    /// Unlike `Room::get_new_messages`, failures are reported as `Error`.
+    /// If this is called again, also on a clone of the room object, before
+    /// the returned future has finished, the request of the second call is
+    /// only sent afterwards, so that no event is returned twice.
+    pub fn get_new_messages(&self) -> AsyncResult<Vec<RoomEvent>> {
+        let room = self.clone();
+        self.sync_lock.run(move || -> AsyncResult<Vec<RoomEvent>> {
+            let call = {
+                let latest_since = room.latest_since.borrow();
+                new_events_call(&room.id, room.peeking, latest_since.as_ref())
+            };
+            let call = match call {
+                Some(call) => call,
+                None => return Box::new(future::ok(Vec::new())),
+            };
+            let latest_since = room.latest_since.clone();
+            Box::new(room.call(call).map(move |(since, events)| {
+                if since.is_some() {
+                    *latest_since.borrow_mut() = since;
+                }
+                events
+            }))
+        })
+    }
+
+    /// Send a message to a room, see `Room::send_message`
+    pub fn send_message(&self, message: Message) -> AsyncResult<EventId> {
+        self.send_message_with_transaction_id(message, &Room::new_transaction_id())
+    }
+
+    /// Send a message to a room with the given transaction ID, see
+    /// `Room::send_message_with_transaction_id`
+    pub fn send_message_with_transaction_id(
+        &self,
+        message: Message,
+        txn_id: &str,
+    ) -> AsyncResult<EventId> {
+        self.call(send_message_call(&self.id, message, txn_id))
+    }
+
+    /// Invite someone to a room, see `Room::invite`
+    pub fn invite(&self, user_id: &UserId) -> AsyncResult<()> {
+        self.change_membership("invite", user_id, None)
+    }
+
+    /// Leave the room, see `Room::leave`
+    pub fn leave(&self) -> AsyncResult<()> {
+        self.call(membership_call(&self.id, "leave", None, None))
+    }
+
+    /// Forget the room, see `Room::forget`
+    pub fn forget(&self) -> AsyncResult<()> {
+        self.call(membership_call(&self.id, "forget", None, None))
+    }
+
+    /// Kick a user from the room, see `Room::kick`
+    pub fn kick(&self, user_id: &UserId, reason: Option<&str>) -> AsyncResult<()> {
+        self.change_membership("kick", user_id, reason)
+    }
+
+    /// Ban a user from the room, see `Room::ban`
+    pub fn ban(&self, user_id: &UserId, reason: Option<&str>) -> AsyncResult<()> {
+        self.change_membership("ban", user_id, reason)
+    }
+
+    /// Lift the ban of a user, see `Room::unban`
+    pub fn unban(&self, user_id: &UserId) -> AsyncResult<()> {
+        self.change_membership("unban", user_id, None)
+    }
+
+    fn change_membership(
+        &self,
+        action: &str,
+        user_id: &UserId,
+        reason: Option<&str>,
+    ) -> AsyncResult<()> {
+        self.call(membership_call(&self.id, action, Some(user_id), reason))
+    }
+
+    /// Returns the aliases of this room on the homeserver of the user, see
+    /// `Room::aliases`
+    pub fn aliases(&self) -> AsyncResult<Vec<RoomAliasId>> {
+        self.call(aliases_call(&self.id))
+    }
+
+    /// Returns the members of the room, see `Room::members`
+    pub fn members(&self, membership: Option<Membership>) -> AsyncResult<Vec<Member>> {
+        let latest_since = self.latest_since.borrow().clone();
+        self.call(members_call(&self.id, membership, latest_since.as_ref()))
+    }
+
+    /// Returns the membership, display name and avatar of a user in the room,
+    /// see `Room::member`
+    pub fn member(&self, user_id: &UserId) -> AsyncResult<Member> {
+        self.call(member_call(&self.id, user_id))
+    }
+
+    /// Returns the aliases clients show for this room, see
+    /// `Room::canonical_alias`
+    pub fn canonical_alias(&self) -> AsyncResult<CanonicalAlias> {
+        self.call(canonical_alias_call(&self.id))
+    }
+
+    /// Sets the aliases clients show for this room, see
+    /// `Room::set_canonical_alias`
+    pub fn set_canonical_alias(&self, content: &CanonicalAlias) -> AsyncResult<EventId> {
+        self.call(set_canonical_alias_call(&self.id, content))
+    }
+
+    /// Returns a link to an event in this room, see `Room::permalink`
+    pub fn permalink(&self, event_id: &EventId) -> Permalink {
+        event_permalink(&self.info, &self.id, event_id)
+    }
+
+    /// Send a message of type `text` to a room, see `Room::send_text`
+    pub fn send_text(&self, text: String) -> AsyncResult<EventId> {
+        self.send_message(Message::Text(text))
+    }
+
+    /// Send a message of type `emote` to a room, see `Room::send_emote`
+    pub fn send_emote(&self, text: String) -> AsyncResult<EventId> {
+        self.send_message(Message::Emote(text))
+    }
+
+    /// Send a message of type `notice` to a room, see `Room::send_notice`
+    pub fn send_notice(&self, text: String) -> AsyncResult<EventId> {
+        self.send_message(Message::Notice(text))
+    }
+
+    /// Put a message into the outbox of the room, see `Room::enqueue`
+    ///
+    /// The state store is accessed on a separate thread.
+    pub fn enqueue(&self, message: Message) -> AsyncResult<String> {
+        let (info, room_id) = (self.info.clone(), self.id.clone());
+        run_blocking(move || Ok(outbox::enqueue(&info, &room_id, message)?))
+    }
+
+    /// Get all messages in the outbox of the room, see `Room::outbox`
+    pub fn outbox(&self) -> AsyncResult<Vec<OutboxEntry>> {
+        let (info, room_id) = (self.info.clone(), self.id.clone());
+        run_blocking(move || Ok(outbox::load(&info, &room_id)?))
+    }
+
+    /// Send all pending messages in the outbox of the room in order, see
+    /// `Room::flush_outbox`
+    pub fn flush_outbox(&self) -> AsyncResult<()> {
+        let (info, room_id) = (self.info.clone(), self.id.clone());
+        let entries = run_blocking(move || Ok(outbox::pending(&info, &room_id)?));
+
+        let room = self.clone();
+        Box::new(entries.and_then(move |entries| {
+            future::loop_fn(entries.into_iter(), move |mut entries| {
+                let entry = match entries.next() {
+                    Some(entry) => entry,
+                    None => return Box::new(future::ok(Loop::Break(()))) as AsyncResult<_>,
+                };
+
+                let (info, room_id) = (room.info.clone(), room.id.clone());
+                let send = room
+                    .send_message_with_transaction_id(entry.message.clone(), &entry.transaction_id);
+                Box::new(send.then(move |res| {
+                    let status = match outbox::delivery_status(res) {
+                        Ok(status) => status,
+                        Err(error) => return fail(error),
+                    };
+                    Box::new(run_blocking(move || {
+                        outbox::set_status(&info, &room_id, &entry.transaction_id, status)?;
+                        Ok(Loop::Continue(entries))
+                    }))
+                }))
+            })
+        }))
+    }
+
+    /// Remove all sent and failed messages from the outbox of the room, see
+    /// `Room::prune_outbox`
+    pub fn prune_outbox(&self) -> AsyncResult<()> {
+        let (info, room_id) = (self.info.clone(), self.id.clone());
+        run_blocking(move || Ok(outbox::prune(&info, &room_id)?))
+    }
+}
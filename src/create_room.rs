@@ -0,0 +1,214 @@
+//! Options for creating rooms
+//!
+//! See `Homeserver::create_room_with`.
+
+use serde_json::Value;
+
+use UserId;
+
+/// Whether a room is listed in the public room directory
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomVisibility {
+    Public,
+    Private,
+}
+
+/// A set of initial state events a room is created with
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomPreset {
+    /// Only invited users may join, guests may join
+    PrivateChat,
+    /// Like `PrivateChat`, but all invited users get the same power level as
+    /// the creator
+    TrustedPrivateChat,
+    /// Everyone may join, guests may not
+    PublicChat,
+}
+
+/// An invite of a user identified by a third-party identifier, e. g. an
+/// email address
+#[derive(Serialize, Debug, Clone)]
+pub struct ThirdPartyInvite {
+    /// The identity server to look up the identifier on, e. g. `vector.im`
+    pub id_server: String,
+    /// An access token for the identity server
+    pub id_access_token: String,
+    /// The kind of identifier, e. g. `email`
+    pub medium: String,
+    /// The identifier, e. g. the email address
+    pub address: String,
+}
+
+/// A state event a room is created with
+#[derive(Serialize, Debug, Clone)]
+pub struct InitialStateEvent {
+    /// The type of the event, e. g. `m.room.history_visibility`
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// The state key, which is empty for most event types
+    pub state_key: String,
+    /// The content of the event
+    pub content: Value,
+}
+
+/// The options a room is created with
+///
+/// All options are optional. The homeserver chooses defaults for options
+/// that are not set, e. g. the preset `private_chat` for rooms that are not
+/// listed in the room directory.
+///
+/// # Examples
+///
+/// ```
+/// let options = CreateRoomBuilder::new()
+///     .name("Bot announcements")
+///     .preset(RoomPreset::PrivateChat)
+///     .invite(&"@admin:example.org".parse()?);
+/// let room = server.create_room_with(options)?;
+/// ```
+///
+/// Spaces are rooms with a special creation content:
+///
+/// ```
+/// let options = CreateRoomBuilder::new()
+///     .name("Our bots")
+///     .creation_content(json!({ "type": "m.space" }));
+/// let space = server.create_room_with(options)?;
+/// ```
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CreateRoomBuilder {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visibility: Option<RoomVisibility>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preset: Option<RoomPreset>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_alias_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    invite: Vec<UserId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    invite_3pid: Vec<ThirdPartyInvite>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_version: Option<String>,
+    #[serde(skip_serializing_if = "is_false")]
+    is_direct: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    initial_state: Vec<InitialStateEvent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power_level_content_override: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    creation_content: Option<Value>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl CreateRoomBuilder {
+    pub fn new() -> Self {
+        CreateRoomBuilder::default()
+    }
+
+    /// Set whether the room is listed in the public room directory
+    pub fn visibility(mut self, visibility: RoomVisibility) -> Self {
+        self.visibility = Some(visibility);
+        self
+    }
+
+    /// Set the preset of initial state events, e. g. who may join
+    pub fn preset(mut self, preset: RoomPreset) -> Self {
+        self.preset = Some(preset);
+        self
+    }
+
+    /// Create an alias for the room
+    ///
+    /// * `alias_name` – The localpart of the alias, e. g. `bots` for
+    ///   `#bots:example.org`. The server part is the server name of the
+    ///   homeserver.
+    ///
+    /// If the alias already exists, creating the room fails with
+    /// `Error::RoomAliasInUse`.
+    pub fn alias_name(mut self, alias_name: &str) -> Self {
+        self.room_alias_name = Some(alias_name.to_owned());
+        self
+    }
+
+    /// Set the name of the room
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Set the topic of the room
+    pub fn topic(mut self, topic: &str) -> Self {
+        self.topic = Some(topic.to_owned());
+        self
+    }
+
+    /// Invite a user to the room
+    ///
+    /// Can be called several times to invite several users.
+    pub fn invite(mut self, user_id: &UserId) -> Self {
+        self.invite.push(user_id.clone());
+        self
+    }
+
+    /// Invite a user identified by a third-party identifier to the room
+    pub fn invite_3pid(mut self, invite: ThirdPartyInvite) -> Self {
+        self.invite_3pid.push(invite);
+        self
+    }
+
+    /// Set the room version, e. g. `11`
+    ///
+    /// By default, the homeserver's default room version is used, see
+    /// `Capabilities::room_versions`.
+    pub fn room_version(mut self, room_version: &str) -> Self {
+        self.room_version = Some(room_version.to_owned());
+        self
+    }
+
+    /// Mark the room as direct chat with the invited users
+    pub fn is_direct(mut self, is_direct: bool) -> Self {
+        self.is_direct = is_direct;
+        self
+    }
+
+    /// Add a state event the room is created with
+    ///
+    /// It overrides the state event of the preset with the same type and
+    /// state key.
+    pub fn initial_state(mut self, event_type: &str, state_key: &str, content: Value) -> Self {
+        self.initial_state.push(InitialStateEvent {
+            event_type: event_type.to_owned(),
+            state_key: state_key.to_owned(),
+            content,
+        });
+        self
+    }
+
+    /// Set fields of the `m.room.power_levels` event, overriding the ones of
+    /// the preset
+    pub fn power_level_content_override(mut self, content: Value) -> Self {
+        self.power_level_content_override = Some(content);
+        self
+    }
+
+    /// Set extra fields of the `m.room.create` event, e. g.
+    /// `{ "type": "m.space" }` to create a space
+    pub fn creation_content(mut self, content: Value) -> Self {
+        self.creation_content = Some(content);
+        self
+    }
+
+    /// Returns the localpart of the alias to create, if any
+    pub(crate) fn get_alias_name(&self) -> Option<&str> {
+        self.room_alias_name.as_deref()
+    }
+}
@@ -0,0 +1,223 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::str;
+
+use futures::{Future, Stream};
+use reqwest;
+use reqwest::unstable::async;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use uia::UiaInfo;
+use AsyncResult;
+
+/// An error returned by the homeserver
+#[derive(Deserialize, Debug, Clone)]
+pub struct MatrixError {
+    /// The HTTP status code of the response
+    #[serde(skip)]
+    pub status: u16,
+    /// The Matrix error code, e. g. `M_FORBIDDEN`
+    pub errcode: String,
+    /// A human readable error message
+    #[serde(default)]
+    pub error: String,
+    /// Whether the client may keep its local state after an
+    /// `M_UNKNOWN_TOKEN` error, e. g. because the access token merely expired
+    #[serde(default)]
+    pub soft_logout: bool,
+    /// How long to wait before sending the request again after
+    /// `M_LIMIT_EXCEEDED`, in milliseconds
+    ///
+    /// If the homeserver does not include it in the body, it is taken from
+    /// the `Retry-After` header.
+    pub retry_after_ms: Option<u64>,
+}
+
+/// An error that occured while talking to the homeserver
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or the response could not be read
+    Http(reqwest::Error),
+    /// The homeserver rejected the request
+    Matrix(MatrixError),
+    /// The homeserver requires user-interactive authentication with stages
+    /// that cannot be completed with the given credentials
+    ///
+    /// The flows and parameters the homeserver offers are included, e. g. the
+    /// public key for `m.login.recaptcha` or the policies for `m.login.terms`.
+    Uia(Box<UiaInfo>),
+    /// The homeserver could not be discovered
+    Discovery(String),
+    /// The request failed every time it was sent, e. g. because the
+    /// homeserver rate limited it or was unreachable
+    ///
+    /// Contains the number of attempts and the error of the last attempt.
+    /// See `RetryPolicy`.
+    RetriesExhausted { attempts: u32, error: Box<Error> },
+    /// The state store could not be read or written
+    Store(io::Error),
+    /// A string is not a valid Matrix identifier, e. g. a user ID without
+    /// server name
+    InvalidIdentifier(String),
+    /// A room could not be created, since the alias with the given localpart
+    /// already exists
+    RoomAliasInUse(String),
+    /// The homeserver refused to change the membership of another user,
+    /// usually because the power level of the user is too low
+    ///
+    /// Contains the error message of the homeserver.
+    InsufficientPowerLevel(String),
+    /// The local HTTP listener waiting for the single sign-on redirect
+    /// failed, see `HomeserverBuilder::login_sso`
+    SsoListener(io::Error),
+    /// The homeserver does not offer the login type needed, e. g.
+    /// `m.login.password`, see `HomeserverBuilder::login_flows`
+    UnsupportedLogin(String),
+    /// The body of a request could not be serialized as JSON
+    Json(serde_json::Error),
+}
+
+impl Error {
+    /// Returns the Matrix error code, if the homeserver rejected the request
+    pub fn errcode(&self) -> Option<&str> {
+        match *self {
+            Error::Matrix(ref error) => Some(&error.errcode),
+            Error::RetriesExhausted { ref error, .. } => error.errcode(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Http(ref error) => write!(f, "HTTP error: {}", error),
+            Error::Matrix(ref error) => write!(
+                f,
+                "Homeserver returned {} ({}): {}",
+                error.errcode, error.status, error.error
+            ),
+            Error::Uia(_) => write!(
+                f,
+                "Homeserver requires authentication stages that cannot be completed"
+            ),
+            Error::Discovery(ref reason) => write!(f, "Homeserver discovery failed: {}", reason),
+            Error::RetriesExhausted {
+                attempts,
+                ref error,
+            } => write!(f, "Request failed after {} attempts: {}", attempts, error),
+            Error::Store(ref error) => write!(f, "State store error: {}", error),
+            Error::InvalidIdentifier(ref reason) => write!(f, "{}", reason),
+            Error::RoomAliasInUse(ref alias_name) => {
+                write!(f, "The room alias `{}` is already in use", alias_name)
+            }
+            Error::InsufficientPowerLevel(ref reason) => {
+                write!(f, "Insufficient power level: {}", reason)
+            }
+            Error::SsoListener(ref error) => write!(f, "SSO listener error: {}", error),
+            Error::Json(ref error) => write!(f, "Invalid request body: {}", error),
+            Error::UnsupportedLogin(ref login_type) => write!(
+                f,
+                "Homeserver does not offer the login type `{}`",
+                login_type
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Http(ref error) => Some(error),
+            Error::RetriesExhausted { ref error, .. } => Some(&**error),
+            Error::Store(ref error) | Error::SsoListener(ref error) => Some(error),
+            Error::Json(ref error) => Some(error),
+            Error::Matrix(_)
+            | Error::Uia(_)
+            | Error::Discovery(_)
+            | Error::InvalidIdentifier(_)
+            | Error::RoomAliasInUse(_)
+            | Error::InsufficientPowerLevel(_)
+            | Error::UnsupportedLogin(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Http(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Store(error)
+    }
+}
+
+/// Reads the JSON body of a successful response, or the Matrix error of an
+/// unsuccessful one
+pub(crate) fn parse_response<T: DeserializeOwned + 'static>(
+    mut res: async::Response,
+) -> AsyncResult<T> {
+    let status = res.status();
+    if status.is_success() {
+        return Box::new(res.json().from_err());
+    }
+
+    let retry_after_ms = retry_after_ms(res.headers());
+    Box::new(
+        read_body(res)
+            .and_then(move |body| Err(response_error(status.as_u16(), retry_after_ms, body))),
+    )
+}
+
+/// Reads the body of a response as text
+pub(crate) fn read_body(res: async::Response) -> AsyncResult<String> {
+    Box::new(
+        res.into_body()
+            .fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, reqwest::Error>(body)
+            })
+            .from_err()
+            .map(|body| String::from_utf8_lossy(&body).into_owned()),
+    )
+}
+
+/// Creates the error for an unsuccessful response, using the delay of the
+/// `Retry-After` header if the body does not contain one
+fn response_error(status: u16, retry_after_ms: Option<u64>, body: String) -> Error {
+    let mut error = parse_matrix_error(status, body);
+    if error.retry_after_ms.is_none() {
+        error.retry_after_ms = retry_after_ms;
+    }
+    Error::Matrix(error)
+}
+
+/// Creates the error for an unsuccessful response from its status code and
+/// body
+pub(crate) fn matrix_error(status: u16, body: String) -> Error {
+    Error::Matrix(parse_matrix_error(status, body))
+}
+
+fn parse_matrix_error(status: u16, body: String) -> MatrixError {
+    let mut error: MatrixError = serde_json::from_str(&body).unwrap_or(MatrixError {
+        status: 0,
+        errcode: "M_UNKNOWN".to_owned(),
+        error: body,
+        soft_logout: false,
+        retry_after_ms: None,
+    });
+    error.status = status;
+    error
+}
+
+/// Reads the `Retry-After` header, if it contains a delay in seconds
+fn retry_after_ms(headers: &reqwest::header::Headers) -> Option<u64> {
+    let value = headers.get_raw("Retry-After")?.one()?;
+    let seconds: u64 = str::from_utf8(value).ok()?.trim().parse().ok()?;
+    Some(seconds.saturating_mul(1000))
+}
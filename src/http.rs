@@ -0,0 +1,165 @@
+//! Configuration of the HTTP client
+//!
+//! The options are set on the `HomeserverBuilder`. The same client is used
+//! for all requests of the builder, the homeserver object and its rooms.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::header::{Headers, UserAgent};
+use reqwest::unstable::async;
+use reqwest::{self, Certificate, Proxy};
+use tokio_core::reactor::Handle;
+
+use error::Error;
+use reactor::Reactor;
+
+/// A root certificate to trust in addition to the system's ones
+///
+/// `reqwest::Certificate` cannot be cloned, so the encoded certificate is
+/// kept and parsed again whenever a client is built.
+#[derive(Debug, Clone)]
+enum RootCertificate {
+    Der(Vec<u8>),
+    Pem(Vec<u8>),
+}
+
+impl RootCertificate {
+    fn parse(&self) -> reqwest::Result<Certificate> {
+        match *self {
+            RootCertificate::Der(ref der) => Certificate::from_der(der),
+            RootCertificate::Pem(ref pem) => Certificate::from_pem(pem),
+        }
+    }
+}
+
+/// Builds the HTTP client for the reactor of the given handle
+pub(crate) type ClientBuilder = Arc<dyn Fn(&Handle) -> async::Client + Send + Sync>;
+
+/// The options the HTTP client is built with
+///
+/// The reactor running the requests of the blocking API is started with a
+/// client built from these options when it is first needed and then reused
+/// until an option changes.
+#[derive(Clone, Default)]
+pub(crate) struct HttpConfig {
+    proxies: Vec<Proxy>,
+    root_certificates: Vec<RootCertificate>,
+    timeout: Option<Duration>,
+    user_agent: Option<String>,
+    /// Builds a client configured by the user, which replaces all other
+    /// options
+    client: Option<ClientBuilder>,
+    /// The reactor of the blocking API
+    ///
+    /// It is replaced rather than cleared when an option changes, since it is
+    /// shared with the clones of this configuration.
+    reactor: Arc<Mutex<Option<Arc<Reactor>>>>,
+}
+
+impl HttpConfig {
+    /// Returns a configuration that uses clients built by `client` for all
+    /// requests
+    pub(crate) fn with_client(client: ClientBuilder) -> Self {
+        HttpConfig {
+            client: Some(client),
+            ..HttpConfig::default()
+        }
+    }
+
+    pub(crate) fn add_proxy(&mut self, proxy: Proxy) {
+        self.proxies.push(proxy);
+        self.changed();
+    }
+
+    /// Adds a DER encoded root certificate, failing if it is invalid
+    pub(crate) fn add_root_certificate_der(&mut self, der: &[u8]) -> Result<(), Error> {
+        self.add_root_certificate(RootCertificate::Der(der.to_owned()))
+    }
+
+    /// Adds a PEM encoded root certificate, failing if it is invalid
+    pub(crate) fn add_root_certificate_pem(&mut self, pem: &[u8]) -> Result<(), Error> {
+        self.add_root_certificate(RootCertificate::Pem(pem.to_owned()))
+    }
+
+    fn add_root_certificate(&mut self, certificate: RootCertificate) -> Result<(), Error> {
+        certificate.parse()?;
+        self.root_certificates.push(certificate);
+        self.changed();
+        Ok(())
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+        self.changed();
+    }
+
+    pub(crate) fn set_user_agent(&mut self, user_agent: &str) {
+        self.user_agent = Some(user_agent.to_owned());
+        self.changed();
+    }
+
+    pub(crate) fn set_client(&mut self, client: ClientBuilder) {
+        self.client = Some(client);
+        self.changed();
+    }
+
+    /// Drops the reactor, so that the next one is started with a client
+    /// built with the changed options
+    fn changed(&mut self) {
+        self.reactor = Arc::default();
+    }
+
+    fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        if let Some(ref user_agent) = self.user_agent {
+            headers.set(UserAgent::new(user_agent.clone()));
+        }
+        headers
+    }
+
+    /// Returns the reactor of the blocking API, starting it on the first call
+    ///
+    /// # Panics
+    /// If the reactor or the TLS backend cannot be initialized, this function
+    /// panics.
+    pub(crate) fn reactor(&self) -> Arc<Reactor> {
+        self.reactor
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| {
+                // The reactor thread must not keep the reactor alive itself
+                let http = HttpConfig {
+                    reactor: Arc::default(),
+                    ..self.clone()
+                };
+                Arc::new(Reactor::start(http))
+            })
+            .clone()
+    }
+
+    /// Returns a client running on the reactor of `handle`
+    ///
+    /// # Panics
+    /// If the TLS backend cannot be initialized, this function panics.
+    pub(crate) fn async_client(&self, handle: &Handle) -> async::Client {
+        if let Some(ref client) = self.client {
+            return client(handle);
+        }
+
+        let mut builder = async::Client::builder();
+        for proxy in &self.proxies {
+            builder.proxy(proxy.clone());
+        }
+        for certificate in &self.root_certificates {
+            builder.add_root_certificate(certificate.parse().unwrap());
+        }
+        if let Some(timeout) = self.timeout {
+            builder.timeout(timeout);
+        }
+        builder
+            .default_headers(self.headers())
+            .build(handle)
+            .expect("Building the HTTP client failed")
+    }
+}
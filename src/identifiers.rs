@@ -0,0 +1,571 @@
+//! Matrix identifiers
+//!
+//! Users, rooms, room aliases, events and media are identified by strings
+//! following the grammar of the Matrix specification, e. g.
+//! `@bot:matrix.org` or `#rust:matrix.org`. Each kind has its own type, so
+//! that they cannot be mixed up, and is validated when parsed.
+//!
+//! All identifiers can be parsed with `str::parse` or `parse`, and converted
+//! back with `to_string` or `as_str`. They are serialized as strings.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use error::Error;
+
+/// The maximum length of an identifier in bytes
+const MAX_LENGTH: usize = 255;
+
+macro_rules! identifier {
+    ($(#[$attr:meta])* $name:ident, $kind:expr, $validate:ident) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(String);
+
+        impl $name {
+            /// Parses and validates the identifier
+            pub fn parse(s: &str) -> Result<Self, Error> {
+                match $validate(s) {
+                    Ok(()) => Ok($name(s.to_owned())),
+                    Err(reason) => Err(Error::InvalidIdentifier(format!(
+                        "`{}` is not a valid {}: {}",
+                        s, $kind, reason
+                    ))),
+                }
+            }
+
+            /// Returns the identifier as string
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<Self, Error> {
+                $name::parse(s)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> String {
+                id.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl<'a> PartialEq<&'a str> for $name {
+            fn eq(&self, other: &&'a str) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.0)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                $name::parse(&s).map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+identifier!(
+    /// The name of a homeserver, e. g. `matrix.org` or `localhost:8448`
+    ///
+    /// This is the server part of user IDs and room aliases. It is not
+    /// necessarily the host the homeserver can be reached at, see
+    /// `Homeserver::discover`.
+    ServerName,
+    "server name",
+    validate_server_name
+);
+
+identifier!(
+    /// The ID of a user, e. g. `@bot:matrix.org`
+    UserId,
+    "user ID",
+    validate_user_id
+);
+
+identifier!(
+    /// The ID of a room, e. g. `!OGEhHVWSdvArJzumhm:matrix.org`
+    ///
+    /// Rooms of version 12 and later have IDs without server name, e. g.
+    /// `!31hneApxJ_1o-63DmFrpeqnkFfWppnzWso1JvH3ogLM`.
+    RoomId,
+    "room ID",
+    validate_room_id
+);
+
+identifier!(
+    /// The alias of a room, e. g. `#rust:matrix.org`
+    RoomAliasId,
+    "room alias",
+    validate_room_alias_id
+);
+
+identifier!(
+    /// The ID of an event, e. g. `$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg`
+    ///
+    /// Events in rooms of version 1 and 2 have IDs with server name, e. g.
+    /// `$h29iv0s8:example.org`.
+    EventId,
+    "event ID",
+    validate_event_id
+);
+
+identifier!(
+    /// The URI of a file uploaded to a homeserver, e. g.
+    /// `mxc://matrix.org/SEsfnsuifSDFSSEF`
+    MxcUri,
+    "mxc URI",
+    validate_mxc_uri
+);
+
+impl ServerName {
+    /// Returns the host name or IP address, e. g. `matrix.org` or `[::1]`
+    pub fn host(&self) -> &str {
+        split_port(&self.0).0
+    }
+
+    /// Returns the port, if the server name contains one
+    pub fn port(&self) -> Option<u16> {
+        split_port(&self.0).1.and_then(|port| port.parse().ok())
+    }
+}
+
+impl UserId {
+    /// Creates a user ID from its parts, e. g. `bot` and `matrix.org`
+    pub fn new(localpart: &str, server_name: &ServerName) -> Result<Self, Error> {
+        UserId::parse(&format!("@{}:{}", localpart, server_name))
+    }
+
+    /// Returns the part before the server name without `@`, e. g. `bot`
+    pub fn localpart(&self) -> &str {
+        split_server(&self.0[1..]).0
+    }
+
+    /// Returns the server name of the user's homeserver, e. g. `matrix.org`
+    pub fn server_name(&self) -> ServerName {
+        ServerName(split_server(&self.0[1..]).1.unwrap().to_owned())
+    }
+}
+
+impl RoomId {
+    /// Returns the part before the server name without `!`
+    pub fn localpart(&self) -> &str {
+        split_server(&self.0[1..]).0
+    }
+
+    /// Returns the server name of the homeserver that created the room
+    ///
+    /// Room IDs of room version 12 and later do not contain a server name.
+    pub fn server_name(&self) -> Option<ServerName> {
+        split_server(&self.0[1..])
+            .1
+            .map(|server_name| ServerName(server_name.to_owned()))
+    }
+}
+
+impl RoomAliasId {
+    /// Creates a room alias from its parts, e. g. `rust` and `matrix.org`
+    pub fn new(alias: &str, server_name: &ServerName) -> Result<Self, Error> {
+        RoomAliasId::parse(&format!("#{}:{}", alias, server_name))
+    }
+
+    /// Returns the part before the server name without `#`, e. g. `rust`
+    pub fn localpart(&self) -> &str {
+        split_server(&self.0[1..]).0
+    }
+
+    /// Returns the server name of the homeserver the alias belongs to
+    pub fn server_name(&self) -> ServerName {
+        ServerName(split_server(&self.0[1..]).1.unwrap().to_owned())
+    }
+}
+
+impl EventId {
+    /// Returns the part before the server name without `$`
+    pub fn localpart(&self) -> &str {
+        split_server(&self.0[1..]).0
+    }
+
+    /// Returns the server name of the homeserver that created the event
+    ///
+    /// Only event IDs of room version 1 and 2 contain a server name.
+    pub fn server_name(&self) -> Option<ServerName> {
+        split_server(&self.0[1..])
+            .1
+            .map(|server_name| ServerName(server_name.to_owned()))
+    }
+}
+
+impl MxcUri {
+    /// Creates an mxc URI from its parts
+    pub fn new(server_name: &ServerName, media_id: &str) -> Result<Self, Error> {
+        MxcUri::parse(&format!("mxc://{}/{}", server_name, media_id))
+    }
+
+    /// Returns the server name of the homeserver the file has been uploaded
+    /// to
+    pub fn server_name(&self) -> ServerName {
+        ServerName(split_media_id(&self.0).0.to_owned())
+    }
+
+    /// Returns the ID of the file on its homeserver
+    pub fn media_id(&self) -> &str {
+        split_media_id(&self.0).1
+    }
+}
+
+/// A room ID or a room alias
+///
+/// Rooms can be joined by either, see `Homeserver::join_room`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RoomOrAliasId {
+    Id(RoomId),
+    Alias(RoomAliasId),
+}
+
+impl RoomOrAliasId {
+    /// Parses a room ID or, if it starts with `#`, a room alias
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        if s.starts_with('#') {
+            RoomAliasId::parse(s).map(RoomOrAliasId::Alias)
+        } else {
+            RoomId::parse(s).map(RoomOrAliasId::Id)
+        }
+    }
+
+    /// Returns the room ID or room alias as string
+    pub fn as_str(&self) -> &str {
+        match *self {
+            RoomOrAliasId::Id(ref id) => id.as_str(),
+            RoomOrAliasId::Alias(ref alias) => alias.as_str(),
+        }
+    }
+}
+
+impl FromStr for RoomOrAliasId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        RoomOrAliasId::parse(s)
+    }
+}
+
+impl fmt::Display for RoomOrAliasId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<RoomId> for RoomOrAliasId {
+    fn from(id: RoomId) -> Self {
+        RoomOrAliasId::Id(id)
+    }
+}
+
+impl From<RoomAliasId> for RoomOrAliasId {
+    fn from(alias: RoomAliasId) -> Self {
+        RoomOrAliasId::Alias(alias)
+    }
+}
+
+/// Splits `localpart:server_name` at the first colon
+fn split_server(s: &str) -> (&str, Option<&str>) {
+    match s.find(':') {
+        Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+        None => (s, None),
+    }
+}
+
+/// Splits a valid server name into host and port
+fn split_port(s: &str) -> (&str, Option<&str>) {
+    // IPv6 addresses contain colons themselves
+    let host_end = if s.starts_with('[') {
+        s.find(']').map_or(s.len(), |pos| pos + 1)
+    } else {
+        s.find(':').unwrap_or(s.len())
+    };
+    match s[host_end..].chars().next() {
+        Some(':') => (&s[..host_end], Some(&s[host_end + 1..])),
+        _ => (s, None),
+    }
+}
+
+/// Splits a valid mxc URI into server name and media ID
+fn split_media_id(s: &str) -> (&str, &str) {
+    let rest = &s["mxc://".len()..];
+    let pos = rest.find('/').unwrap();
+    (&rest[..pos], &rest[pos + 1..])
+}
+
+fn validate_server_name(s: &str) -> Result<(), &'static str> {
+    if s.is_empty() || s.len() > MAX_LENGTH {
+        return Err("it must be between 1 and 255 bytes long");
+    }
+
+    let (host, port) = split_port(s);
+    if host.starts_with('[') {
+        if host.len() < 3
+            || !host.ends_with(']')
+            || !host[1..host.len() - 1]
+                .chars()
+                .all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.')
+        {
+            return Err("the IPv6 address is invalid");
+        }
+    } else if host.is_empty()
+        || !host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    {
+        return Err("the host name is invalid");
+    }
+
+    match port {
+        Some(port) if port.len() > 5 || port.parse::<u16>().is_err() => Err("the port is invalid"),
+        _ => Ok(()),
+    }
+}
+
+/// Validates an identifier of the form `{sigil}{localpart}:{server_name}`
+///
+/// If `server_required` is false, the server name may be missing.
+fn validate_sigil_and_server(
+    s: &str,
+    sigil: char,
+    server_required: bool,
+) -> Result<(), &'static str> {
+    if s.len() > MAX_LENGTH {
+        return Err("it must not be longer than 255 bytes");
+    }
+    if !s.starts_with(sigil) {
+        return Err("it starts with the wrong sigil");
+    }
+    let (localpart, server_name) = split_server(&s[1..]);
+    if localpart.is_empty() {
+        return Err("the localpart is empty");
+    }
+    if localpart.contains('\0') {
+        return Err("the localpart contains a NUL character");
+    }
+    match server_name {
+        Some(server_name) => validate_server_name(server_name),
+        None if server_required => Err("the server name is missing"),
+        None => Ok(()),
+    }
+}
+
+fn validate_user_id(s: &str) -> Result<(), &'static str> {
+    validate_sigil_and_server(s, '@', true)?;
+    // Historical user IDs may contain any printable ASCII character
+    if !split_server(&s[1..])
+        .0
+        .chars()
+        .all(|c| c.is_ascii_graphic())
+    {
+        return Err("the localpart contains invalid characters");
+    }
+    Ok(())
+}
+
+fn validate_room_id(s: &str) -> Result<(), &'static str> {
+    validate_sigil_and_server(s, '!', false)
+}
+
+fn validate_room_alias_id(s: &str) -> Result<(), &'static str> {
+    validate_sigil_and_server(s, '#', true)
+}
+
+fn validate_event_id(s: &str) -> Result<(), &'static str> {
+    validate_sigil_and_server(s, '$', false)
+}
+
+fn validate_mxc_uri(s: &str) -> Result<(), &'static str> {
+    if !s.starts_with("mxc://") {
+        return Err("it must start with `mxc://`");
+    }
+    let rest = &s["mxc://".len()..];
+    let (server_name, media_id) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+        None => return Err("the media ID is missing"),
+    };
+    validate_server_name(server_name)?;
+    if media_id.is_empty()
+        || !media_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("the media ID is invalid");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_names() {
+        let server_name = ServerName::parse("matrix.org").unwrap();
+        assert_eq!(server_name.host(), "matrix.org");
+        assert_eq!(server_name.port(), None);
+
+        let server_name = ServerName::parse("localhost:8448").unwrap();
+        assert_eq!(server_name.host(), "localhost");
+        assert_eq!(server_name.port(), Some(8448));
+
+        let server_name = ServerName::parse("[::1]:8448").unwrap();
+        assert_eq!(server_name.host(), "[::1]");
+        assert_eq!(server_name.port(), Some(8448));
+
+        let server_name = ServerName::parse("[2001:db8::1]").unwrap();
+        assert_eq!(server_name.host(), "[2001:db8::1]");
+        assert_eq!(server_name.port(), None);
+
+        assert!(ServerName::parse("").is_err());
+        assert!(ServerName::parse("[::1").is_err());
+        assert!(ServerName::parse("[]").is_err());
+        assert!(ServerName::parse("[::g]").is_err());
+        assert!(ServerName::parse("example.org:").is_err());
+        assert!(ServerName::parse("example.org:65536").is_err());
+        assert!(ServerName::parse("https://example.org").is_err());
+        assert!(ServerName::parse("exa mple.org").is_err());
+    }
+
+    #[test]
+    fn user_ids() {
+        let user_id = UserId::parse("@bot:matrix.org").unwrap();
+        assert_eq!(user_id.localpart(), "bot");
+        assert_eq!(user_id.server_name(), "matrix.org");
+        assert_eq!(user_id.to_string(), "@bot:matrix.org");
+
+        let user_id = UserId::parse("@bot:[::1]:8448").unwrap();
+        assert_eq!(user_id.localpart(), "bot");
+        assert_eq!(user_id.server_name().host(), "[::1]");
+        assert_eq!(user_id.server_name().port(), Some(8448));
+
+        let server_name = ServerName::parse("example.org").unwrap();
+        assert_eq!(
+            UserId::new("bot", &server_name).unwrap(),
+            "@bot:example.org"
+        );
+
+        assert!(UserId::parse("@:matrix.org").is_err());
+        assert!(UserId::parse("bot:matrix.org").is_err());
+        assert!(UserId::parse("#bot:matrix.org").is_err());
+        assert!(UserId::parse("@bot").is_err());
+        assert!(UserId::parse("@bot:").is_err());
+        assert!(UserId::parse("@b ot:matrix.org").is_err());
+        assert!(UserId::parse(&format!("@{}:matrix.org", "a".repeat(255))).is_err());
+    }
+
+    #[test]
+    fn room_ids() {
+        let room_id = RoomId::parse("!OGEhHVWSdvArJzumhm:matrix.org").unwrap();
+        assert_eq!(room_id.localpart(), "OGEhHVWSdvArJzumhm");
+        assert_eq!(room_id.server_name().unwrap(), "matrix.org");
+
+        // Room version 12 and later
+        let room_id = RoomId::parse("!31hneApxJ_1o-63DmFrpeqnkFfWppnzWso1JvH3ogLM").unwrap();
+        assert_eq!(
+            room_id.localpart(),
+            "31hneApxJ_1o-63DmFrpeqnkFfWppnzWso1JvH3ogLM"
+        );
+        assert_eq!(room_id.server_name(), None);
+
+        assert!(RoomId::parse("!:matrix.org").is_err());
+        assert!(RoomId::parse("!").is_err());
+        assert!(RoomId::parse("#room:matrix.org").is_err());
+        assert!(RoomId::parse("!room:").is_err());
+    }
+
+    #[test]
+    fn room_aliases() {
+        let alias = RoomAliasId::parse("#rust:matrix.org").unwrap();
+        assert_eq!(alias.localpart(), "rust");
+        assert_eq!(alias.server_name(), "matrix.org");
+
+        assert!(RoomAliasId::parse("#:matrix.org").is_err());
+        assert!(RoomAliasId::parse("#rust").is_err());
+        assert!(RoomAliasId::parse("!rust:matrix.org").is_err());
+        assert!(RoomAliasId::parse("#bottest:https://matrix.org").is_err());
+    }
+
+    #[test]
+    fn event_ids() {
+        let event_id = EventId::parse("$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg").unwrap();
+        assert_eq!(event_id.server_name(), None);
+
+        let event_id = EventId::parse("$h29iv0s8:example.org").unwrap();
+        assert_eq!(event_id.localpart(), "h29iv0s8");
+        assert_eq!(event_id.server_name().unwrap(), "example.org");
+
+        assert!(EventId::parse("$").is_err());
+        assert!(EventId::parse("h29iv0s8:example.org").is_err());
+    }
+
+    #[test]
+    fn mxc_uris() {
+        let uri = MxcUri::parse("mxc://matrix.org/SEsfnsuifSDFSSEF").unwrap();
+        assert_eq!(uri.server_name(), "matrix.org");
+        assert_eq!(uri.media_id(), "SEsfnsuifSDFSSEF");
+
+        assert!(MxcUri::parse("https://matrix.org/SEsfnsuifSDFSSEF").is_err());
+        assert!(MxcUri::parse("mxc://matrix.org").is_err());
+        assert!(MxcUri::parse("mxc://matrix.org/").is_err());
+        assert!(MxcUri::parse("mxc://matrix.org/a/b").is_err());
+    }
+
+    #[test]
+    fn room_or_alias_ids() {
+        match RoomOrAliasId::parse("#rust:matrix.org").unwrap() {
+            RoomOrAliasId::Alias(alias) => assert_eq!(alias, "#rust:matrix.org"),
+            id => panic!("parsed as {:?}", id),
+        }
+        match RoomOrAliasId::parse("!room:matrix.org").unwrap() {
+            RoomOrAliasId::Id(room_id) => assert_eq!(room_id, "!room:matrix.org"),
+            id => panic!("parsed as {:?}", id),
+        }
+        assert!(RoomOrAliasId::parse("@bot:matrix.org").is_err());
+    }
+
+    #[test]
+    fn serde() {
+        let user_id: UserId = serde_json::from_str(r#""@bot:matrix.org""#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&user_id).unwrap(),
+            r#""@bot:matrix.org""#
+        );
+        assert!(serde_json::from_str::<UserId>(r#""bot""#).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Invites to rooms and which of them to accept automatically
+//!
+//! See `Homeserver::get_invites` and `Homeserver::accept_invites`.
+
+use serde_json::Value;
+
+use error::Error;
+use {MxcUri, RoomId, ServerName, UserId};
+
+/// An invite to a room
+///
+/// Until the invite is accepted, only a few state events of the room are
+/// known, so the name and avatar of the room may be missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    /// The room the user has been invited to
+    pub room_id: RoomId,
+    /// The user who sent the invite
+    pub inviter: Option<UserId>,
+    /// The name of the room
+    pub room_name: Option<String>,
+    /// The avatar of the room
+    pub room_avatar: Option<MxcUri>,
+    /// Whether the inviter marked the room as direct chat
+    pub is_direct: bool,
+    /// The reason given by the inviter
+    pub reason: Option<String>,
+}
+
+impl Invite {
+    /// Parses an invite from the stripped state of an invited room in a sync
+    /// response
+    ///
+    /// * `user_id` – The ID of the logged in user. If it is unknown, the
+    ///   first invite membership event is taken as the user's invite.
+    ///
+    /// If there is no invite membership event, `None` is returned.
+    // `Option::is_none_or` needs Rust 1.82
+    #[allow(clippy::unnecessary_map_or)]
+    fn parse(room_id: RoomId, events: &[Value], user_id: Option<&UserId>) -> Option<Self> {
+        let state = |event_type: &str| {
+            events
+                .iter()
+                .find(|event| event["type"] == event_type && event["state_key"] == "")
+                .map(|event| &event["content"])
+        };
+        let member = events.iter().find(|event| {
+            event["type"] == "m.room.member"
+                && (event["content"]["membership"] == "invite" || event["membership"] == "invite")
+                && user_id.map_or(true, |user_id| event["state_key"] == user_id.as_str())
+        })?;
+
+        Some(Invite {
+            room_id,
+            inviter: member["sender"]
+                .as_str()
+                .and_then(|sender| UserId::parse(sender).ok()),
+            room_name: state("m.room.name")
+                .and_then(|content| content["name"].as_str())
+                .map(str::to_owned),
+            room_avatar: state("m.room.avatar")
+                .and_then(|content| content["url"].as_str())
+                .and_then(|url| MxcUri::parse(url).ok()),
+            is_direct: member["content"]["is_direct"].as_bool().unwrap_or(false),
+            reason: member["content"]["reason"].as_str().map(str::to_owned),
+        })
+    }
+}
+
+/// The invites handled by `Homeserver::accept_invites`, each with the joined
+/// room, `None` if it has been declined or left pending, or the error of
+/// joining or declining
+pub type InviteOutcomes<R> = Vec<(Invite, Result<Option<R>, Error>)>;
+
+/// Returns the invites from a sync response, one per room
+pub(crate) fn invites_from_sync(v: &Value, user_id: Option<&UserId>) -> Vec<Invite> {
+    let rooms = match v["rooms"]["invite"].as_object() {
+        Some(rooms) => rooms,
+        None => return Vec::new(),
+    };
+
+    rooms
+        .iter()
+        .filter_map(|(room, info)| {
+            let room_id = RoomId::parse(room).ok()?;
+            let events = info["invite_state"]["events"].as_array()?;
+            Invite::parse(room_id, events, user_id)
+        })
+        .collect()
+}
+
+/// Decides which invites are accepted by `Homeserver::accept_invites`
+///
+/// By default, no invite is accepted and the others are left pending.
+///
+/// # Examples
+///
+/// Accept invites from an admin and from everyone on the own server, and
+/// decline all other invites:
+///
+/// ```
+/// let policy = InvitePolicy::new()
+///     .allow_inviter(&"@admin:example.org".parse()?)
+///     .allow_server(&"bots.example.org".parse()?)
+///     .decline_others(true);
+/// for (invite, outcome) in server.accept_invites(&policy)? {
+///     if let Err(error) = outcome {
+///         println!("Handling the invite to {} failed: {}", invite.room_id, error);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct InvitePolicy {
+    accept_all: bool,
+    inviters: Vec<UserId>,
+    servers: Vec<ServerName>,
+    decline_others: bool,
+}
+
+impl InvitePolicy {
+    pub fn new() -> Self {
+        InvitePolicy::default()
+    }
+
+    /// A policy that accepts all invites
+    pub fn accept_all() -> Self {
+        InvitePolicy {
+            accept_all: true,
+            ..InvitePolicy::default()
+        }
+    }
+
+    /// Accept invites sent by the given user
+    pub fn allow_inviter(mut self, user_id: &UserId) -> Self {
+        self.inviters.push(user_id.clone());
+        self
+    }
+
+    /// Accept invites sent by users of the given server
+    pub fn allow_server(mut self, server_name: &ServerName) -> Self {
+        self.servers.push(server_name.clone());
+        self
+    }
+
+    /// Set whether invites that are not accepted are declined instead of
+    /// being left pending
+    pub fn decline_others(mut self, decline_others: bool) -> Self {
+        self.decline_others = decline_others;
+        self
+    }
+
+    /// Returns whether the invite is accepted
+    ///
+    /// Invites without a known inviter are only accepted by `accept_all`.
+    pub fn accepts(&self, invite: &Invite) -> bool {
+        if self.accept_all {
+            return true;
+        }
+        match invite.inviter {
+            Some(ref inviter) => {
+                self.inviters.contains(inviter) || self.servers.contains(&inviter.server_name())
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether invites that are not accepted are declined
+    pub fn declines_others(&self) -> bool {
+        self.decline_others
+    }
+
+    /// Returns what to do with the invite
+    pub(crate) fn action(&self, invite: &Invite) -> InviteAction {
+        if self.accepts(invite) {
+            InviteAction::Accept
+        } else if self.decline_others {
+            InviteAction::Decline
+        } else {
+            InviteAction::Keep
+        }
+    }
+}
+
+/// What `accept_invites` does with an invite, see `InvitePolicy::action`
+pub(crate) enum InviteAction {
+    Accept,
+    Decline,
+    /// Leave the invite pending
+    Keep,
+}
@@ -12,25 +12,80 @@
 
 #[macro_use]
 extern crate serde_derive;
+extern crate futures;
 extern crate reqwest;
+extern crate serde;
+extern crate tokio_core;
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate url;
 
+mod async_client;
+mod create_room;
+mod error;
+mod http;
+mod identifiers;
+mod invite;
+mod members;
+mod outbox;
+mod permalink;
+mod reactor;
+mod request;
+mod retry;
+mod store;
+mod uia;
+
+use async_client::{fail, AsyncLock, RoomState};
+#[cfg(not(feature = "async"))]
+use async_client::{AsyncHomeserver, AsyncResult, AsyncRoom};
+#[cfg(feature = "async")]
+pub use async_client::{AsyncHomeserver, AsyncResult, AsyncRoom};
+pub use create_room::{
+    CreateRoomBuilder, InitialStateEvent, RoomPreset, RoomVisibility, ThirdPartyInvite,
+};
+pub use error::{Error, MatrixError};
+use http::HttpConfig;
+pub use identifiers::{EventId, MxcUri, RoomAliasId, RoomId, RoomOrAliasId, ServerName, UserId};
+use invite::invites_from_sync;
+pub use invite::{Invite, InviteOutcomes, InvitePolicy};
+use members::{joined_members, members_from_chunk, MemberContent};
+pub use members::{Member, Membership};
+use outbox::OutboxLocks;
+pub use outbox::{DeliveryStatus, OutboxEntry};
+pub use permalink::{MatrixId, Permalink};
+use reactor::Reactor;
+use request::{with_fallback, ApiVersion, Auth, Call, Request};
+use retry::retry;
+pub use retry::RetryPolicy;
+pub use store::{FileStore, MemoryStore, StateStore};
+pub use uia::{UiaCredentials, UiaFlow, UiaInfo};
+
+use futures::{future, Future};
+use reqwest::unstable::async;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
+use std::cmp;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Handle;
 
-use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET, USERINFO_ENCODE_SET};
-define_encode_set! {
-    pub ACCESS_TOKEN_ENCODE_SET = [USERINFO_ENCODE_SET] | {
-        '%', '&'
-    }
-}
+/// Shown in place of access tokens and refresh tokens in `Debug` output
+const REDACTED: &str = "<redacted>";
 
 #[derive(Deserialize, Debug)]
 struct JoinInfo {
-    room_id: String,
+    room_id: RoomId,
+}
+
+#[derive(Deserialize, Debug)]
+struct EventInfo {
+    event_id: EventId,
 }
 
 #[derive(Deserialize, Debug)]
@@ -38,42 +93,648 @@ struct SinceInfo {
     next_batch: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct AccesstokenInfo {
+/// The information returned by the homeserver after a successful login
+#[derive(Deserialize, Clone)]
+pub struct LoginInfo {
+    /// The fully qualified user ID of the logged in user, e. g. `@bot:matrix.org`
+    pub user_id: UserId,
+    /// The access token
+    pub access_token: String,
+    /// The refresh token, if the homeserver issued a short-lived access token
+    pub refresh_token: Option<String>,
+    /// The lifetime of the access token in milliseconds, if it expires
+    pub expires_in_ms: Option<u64>,
+    /// The server name of the homeserver, e. g. `matrix.org`
+    ///
+    /// Deprecated by the specification in favour of the server part of `user_id`.
+    pub home_server: Option<String>,
+    /// The ID of the device the access token has been created for
+    pub device_id: Option<String>,
+    /// Server discovery information the client should use from now on
+    pub well_known: Option<DiscoveryInfo>,
+}
+
+impl fmt::Debug for LoginInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let refresh_token = self.refresh_token.as_ref().map(|_| REDACTED);
+        f.debug_struct("LoginInfo")
+            .field("user_id", &self.user_id)
+            .field("access_token", &REDACTED)
+            .field("refresh_token", &refresh_token)
+            .field("expires_in_ms", &self.expires_in_ms)
+            .field("home_server", &self.home_server)
+            .field("device_id", &self.device_id)
+            .field("well_known", &self.well_known)
+            .finish()
+    }
+}
+
+/// Server discovery information as returned by `/.well-known/matrix/client`
+/// or on login
+#[derive(Deserialize, Debug, Clone)]
+pub struct DiscoveryInfo {
+    /// Information about the homeserver to connect to
+    #[serde(rename = "m.homeserver")]
+    pub homeserver: BaseUrlInfo,
+    /// Information about the identity server to use, if any
+    #[serde(rename = "m.identity_server")]
+    pub identity_server: Option<BaseUrlInfo>,
+}
+
+/// The base URL of a homeserver or identity server
+#[derive(Deserialize, Debug, Clone)]
+pub struct BaseUrlInfo {
+    /// The base URL, e. g. `https://matrix.org`
+    pub base_url: String,
+}
+
+/// The owner of an access token as returned by `/account/whoami`
+#[derive(Deserialize, Debug, Clone)]
+pub struct WhoAmI {
+    /// The fully qualified user ID, e. g. `@bot:matrix.org`
+    pub user_id: UserId,
+    /// The ID of the device the access token belongs to
+    pub device_id: Option<String>,
+    /// Whether the user is a guest user
+    #[serde(default)]
+    pub is_guest: bool,
+}
+
+/// The room a room alias points to as returned by `/directory/room/{roomAlias}`
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoomAliasInfo {
+    /// The ID of the room
+    pub room_id: RoomId,
+    /// Servers that are in the room and can be asked to join it
+    #[serde(default)]
+    pub servers: Vec<ServerName>,
+}
+
+#[derive(Deserialize)]
+struct AliasesInfo {
+    aliases: Vec<RoomAliasId>,
+}
+
+/// The content of the `m.room.canonical_alias` state event of a room
+///
+/// These are the aliases clients show for the room. Each of them must point
+/// to the room, see `Homeserver::create_alias`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CanonicalAlias {
+    /// The main alias of the room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<RoomAliasId>,
+    /// Further aliases of the room
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alt_aliases: Vec<RoomAliasId>,
+}
+
+/// The credentials of a user on a homeserver
+///
+/// When the homeserver issues short-lived access tokens, the access token
+/// and refresh token change over time. Register a callback with
+/// `HomeserverBuilder::on_session_refresh` to persist them.
+#[derive(Clone)]
+pub struct Session {
+    /// The access token
+    pub access_token: String,
+    /// The refresh token, if the homeserver issued one
+    pub refresh_token: Option<String>,
+    /// The fully qualified user ID of the access token owner, if known
+    pub user_id: Option<UserId>,
+    /// The device ID of the access token, if known
+    pub device_id: Option<String>,
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let refresh_token = self.refresh_token.as_ref().map(|_| REDACTED);
+        f.debug_struct("Session")
+            .field("access_token", &REDACTED)
+            .field("refresh_token", &refresh_token)
+            .field("user_id", &self.user_id)
+            .field("device_id", &self.device_id)
+            .finish()
+    }
+}
+
+/// A callback that is called with the new session after the access token has
+/// been refreshed
+type SessionCallback = Arc<dyn Fn(&Session) + Send + Sync>;
+
+#[derive(Deserialize)]
+struct RefreshInfo {
     access_token: String,
+    refresh_token: Option<String>,
 }
 
 /// The information needed to connect to a homeseverer with an access token
-#[derive(Deserialize, Debug, Clone)]
+///
+/// The session is shared between a `Homeserver` and all `Room`s created from
+/// it, so that a refreshed access token is used by all of them.
+#[derive(Clone)]
 struct ServerInfo {
     /// The homeserver URL without trailing slash, e. g. `https://matrix.org`
     server_name: String,
-    /// The access token
-    access_token: String,
+    /// The version path segment of the client-server API, e. g. `r0` or `v3`
+    ///
+    /// This is shared as well, since it is changed to `r0` if the homeserver
+    /// does not support `v3`.
+    api_version: ApiVersion,
+    /// The access token and associated information
+    session: Arc<Mutex<Session>>,
+    /// Whether the access token is sent in the query string instead of the
+    /// `Authorization` header
+    access_token_in_query: bool,
+    /// How failed requests are retried
+    retry_policy: RetryPolicy,
+    /// Where state like the outboxes of rooms is kept
+    store: Arc<dyn StateStore>,
+    /// Held while the outbox of a room is changed
+    outbox_locks: OutboxLocks,
+    /// Called whenever the access token has been refreshed
+    on_session_refresh: Option<SessionCallback>,
+    /// Held while the access token is refreshed, so that the refresh token
+    /// is only used once
+    refresh_lock: AsyncLock,
+}
+
+impl fmt::Debug for ServerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerInfo")
+            .field("server_name", &self.server_name)
+            .field("session", &self.session)
+            .field("access_token_in_query", &self.access_token_in_query)
+            .field("retry_policy", &self.retry_policy)
+            .finish()
+    }
+}
+
+impl ServerInfo {
+    /// Sends an authenticated request and reads its response
+    ///
+    /// If the homeserver reports that the access token has expired and a
+    /// refresh token is available, the access token is refreshed and the
+    /// request is sent once more.
+    fn execute<T: DeserializeOwned + 'static>(
+        &self,
+        client: &async::Client,
+        handle: &Handle,
+        request: Request,
+    ) -> AsyncResult<T> {
+        let info = self.clone();
+        let client = client.clone();
+        let handle = handle.clone();
+        let retry_request = request.clone();
+        let access_token = self.access_token();
+        Box::new(
+            self.execute_once(&client, &handle, request, &access_token)
+                .or_else(move |error| -> AsyncResult<T> {
+                    if !info.can_refresh(&error) {
+                        return Box::new(future::err(error));
+                    }
+                    Box::new(
+                        info.refresh(&client, &handle, access_token)
+                            .and_then(move |()| {
+                                let access_token = info.access_token();
+                                info.execute_once(&client, &handle, retry_request, &access_token)
+                            }),
+                    )
+                }),
+        )
+    }
+
+    /// Returns whether the error says that the access token has merely
+    /// expired and it can be refreshed
+    ///
+    /// After a hard logout, e. g. because the device has been deleted, the
+    /// refresh token is invalid as well.
+    fn can_refresh(&self, error: &Error) -> bool {
+        match *error {
+            Error::Matrix(ref error) => {
+                error.errcode == "M_UNKNOWN_TOKEN"
+                    && error.soft_logout
+                    && self.session.lock().unwrap().refresh_token.is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Makes an authenticated call, see `execute`
+    fn call<R, T>(
+        &self,
+        client: &async::Client,
+        handle: &Handle,
+        call: Call<R, T>,
+    ) -> AsyncResult<T>
+    where
+        R: DeserializeOwned + 'static,
+        T: 'static,
+    {
+        Box::new(
+            self.execute(client, handle, call.request)
+                .then(call.response),
+        )
+    }
+
+    /// Sends an authenticated request with the given access token
+    fn execute_once<T: DeserializeOwned + 'static>(
+        &self,
+        client: &async::Client,
+        handle: &Handle,
+        request: Request,
+        access_token: &str,
+    ) -> AsyncResult<T> {
+        request.execute(
+            client,
+            handle,
+            &self.server_name,
+            &self.api_version,
+            self.auth(access_token),
+            &self.retry_policy,
+        )
+    }
+
+    /// Stores the user ID and device ID reported by `/account/whoami`
+    fn store_whoami(&self, info: &WhoAmI) {
+        let mut session = self.session.lock().unwrap();
+        session.user_id = Some(info.user_id.clone());
+        if info.device_id.is_some() {
+            session.device_id = info.device_id.clone();
+        }
+    }
+
+    fn access_token(&self) -> String {
+        self.session.lock().unwrap().access_token.clone()
+    }
+
+    /// Returns the authentication with the given access token
+    fn auth(&self, access_token: &str) -> Auth {
+        if self.access_token_in_query {
+            Auth::Query(access_token.to_owned())
+        } else {
+            Auth::Header(access_token.to_owned())
+        }
+    }
+
+    /// Exchanges the refresh token for a new access token
+    ///
+    /// Only one refresh runs at a time. If `expired_token` has already been
+    /// replaced, e. g. by another request that failed at the same time, the
+    /// refresh is skipped, since the refresh token has been used up.
+    fn refresh(
+        &self,
+        client: &async::Client,
+        handle: &Handle,
+        expired_token: String,
+    ) -> AsyncResult<()> {
+        let info = self.clone();
+        let client = client.clone();
+        let handle = handle.clone();
+        self.refresh_lock.run(move || -> AsyncResult<()> {
+            if info.access_token() != expired_token {
+                return Box::new(future::ok(()));
+            }
+
+            let request = info.refresh_request().execute(
+                &client,
+                &handle,
+                &info.server_name,
+                &info.api_version,
+                Auth::None,
+                &info.retry_policy,
+            );
+            Box::new(request.map(move |refresh_info: RefreshInfo| info.store_refresh(refresh_info)))
+        })
+    }
+
+    fn refresh_request(&self) -> Request {
+        let mut map: HashMap<&str, String> = HashMap::new();
+        map.insert(
+            "refresh_token",
+            self.session
+                .lock()
+                .unwrap()
+                .refresh_token
+                .clone()
+                .unwrap_or_default(),
+        );
+        Request::post(&["refresh"]).json(&map)
+    }
+
+    /// Stores the new tokens and calls the callback
+    fn store_refresh(&self, info: RefreshInfo) {
+        let session = {
+            let mut session = self.session.lock().unwrap();
+            session.access_token = info.access_token;
+            if info.refresh_token.is_some() {
+                session.refresh_token = info.refresh_token;
+            }
+            session.clone()
+        };
+        if let Some(ref on_session_refresh) = self.on_session_refresh {
+            on_session_refresh(&session);
+        }
+    }
+}
+
+/// Identifies the user when logging in with a password
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum UserIdentifier {
+    /// A Matrix user, identified by the username or the fully qualified user ID
+    #[serde(rename = "m.id.user")]
+    User { user: String },
+    /// A user identified by a third party identifier, e. g. an email address.
+    /// `medium` is e. g. `email`.
+    #[serde(rename = "m.id.thirdparty")]
+    ThirdParty { medium: String, address: String },
+    /// A user identified by a phone number.
+    /// `country` is the two-letter uppercase ISO-3166-1 alpha-2 country code
+    /// the number in `phone` should be parsed as if it were dialled from.
+    #[serde(rename = "m.id.phone")]
+    Phone { country: String, phone: String },
+}
+
+/// A login method advertised by the homeserver
+#[derive(Deserialize, Debug, Clone)]
+pub struct LoginFlow {
+    /// The login type, e. g. `m.login.password`, `m.login.token` or `m.login.sso`
+    #[serde(rename = "type")]
+    pub login_type: String,
+    /// The identity providers offered for `m.login.sso`, if the server lists them
+    #[serde(default)]
+    pub identity_providers: Vec<IdentityProvider>,
+}
+
+/// An identity provider that can be used for single sign-on
+#[derive(Deserialize, Debug, Clone)]
+pub struct IdentityProvider {
+    /// The ID of the identity provider, to be used in the SSO redirect URL
+    pub id: String,
+    /// The human readable name of the identity provider
+    pub name: String,
+    /// An `mxc://` URI of an icon for the identity provider
+    pub icon: Option<String>,
+    /// A brand hint like `github` or `google`
+    pub brand: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginFlows {
+    flows: Vec<LoginFlow>,
+}
+
+#[derive(Serialize)]
+struct LoginRequest<'a> {
+    #[serde(rename = "type")]
+    login_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identifier: Option<&'a UserIdentifier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_device_display_name: Option<&'a str>,
+    refresh_token: bool,
+}
+
+/// Additional information needed to register an account
+///
+/// Which of these are required depends on the homeserver.
+/// If the homeserver requires authentication stages that cannot be completed
+/// with the given options, `Error::Uia` is returned, containing the
+/// parameters for the stages, e. g. the terms of service to accept.
+#[derive(Default, Clone)]
+pub struct RegistrationOptions {
+    /// The token for the stage `m.login.registration_token`
+    pub registration_token: Option<String>,
+    /// The reCAPTCHA response for the stage `m.login.recaptcha`
+    pub recaptcha_response: Option<String>,
+    /// Whether the user accepted the terms of service for the stage
+    /// `m.login.terms`
+    pub accept_terms: bool,
+}
+
+impl fmt::Debug for RegistrationOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registration_token = self.registration_token.as_ref().map(|_| REDACTED);
+        f.debug_struct("RegistrationOptions")
+            .field("registration_token", &registration_token)
+            .field("recaptcha_response", &self.recaptcha_response)
+            .field("accept_terms", &self.accept_terms)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct RegisterRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_device_display_name: Option<String>,
+    refresh_token: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<Value>,
+}
+
+/// The versions of the client-server API a homeserver supports
+#[derive(Deserialize, Debug, Clone)]
+pub struct Versions {
+    /// The supported versions, e. g. `r0.6.1` or `v1.1`
+    pub versions: Vec<String>,
+    /// Experimental features and whether they are enabled, e. g.
+    /// `org.matrix.msc3440.stable`
+    #[serde(default)]
+    pub unstable_features: HashMap<String, bool>,
+}
+
+impl Versions {
+    /// Returns whether the homeserver supports the given version, e. g. `v1.1`
+    pub fn supports_version(&self, version: &str) -> bool {
+        self.versions.iter().any(|v| v == version)
+    }
+
+    /// Returns whether the homeserver has enabled the given experimental
+    /// feature
+    pub fn supports_unstable_feature(&self, feature: &str) -> bool {
+        self.unstable_features.get(feature) == Some(&true)
+    }
+
+    /// Returns the path segment of the newest supported API version in the
+    /// endpoint URLs, `v3` or `r0`
+    fn api_version(&self) -> &'static str {
+        if self
+            .versions
+            .iter()
+            .any(|version| version.starts_with("v1."))
+        {
+            "v3"
+        } else {
+            "r0"
+        }
+    }
+}
+
+/// What the homeserver allows the user to do, as returned by `/capabilities`
+///
+/// Capabilities the homeserver does not mention take the default value of
+/// the specification.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Capabilities {
+    /// Whether the user can change their password
+    #[serde(
+        rename = "m.change_password",
+        default = "enabled",
+        deserialize_with = "boolean_capability"
+    )]
+    pub change_password: bool,
+    /// The room versions the homeserver supports, if it reports them
+    #[serde(rename = "m.room_versions")]
+    pub room_versions: Option<RoomVersions>,
+    /// Whether the user can change their display name
+    #[serde(
+        rename = "m.set_displayname",
+        default = "enabled",
+        deserialize_with = "boolean_capability"
+    )]
+    pub set_displayname: bool,
+    /// Whether the user can change their avatar
+    #[serde(
+        rename = "m.set_avatar_url",
+        default = "enabled",
+        deserialize_with = "boolean_capability"
+    )]
+    pub set_avatar_url: bool,
+    /// Whether the user can add, remove and change email addresses and phone
+    /// numbers of their account
+    #[serde(
+        rename = "m.3pid_changes",
+        default = "enabled",
+        deserialize_with = "boolean_capability"
+    )]
+    pub thirdparty_id_changes: bool,
+    /// Whether the user can create login tokens for other devices
+    #[serde(
+        rename = "m.get_login_token",
+        default,
+        deserialize_with = "boolean_capability"
+    )]
+    pub get_login_token: bool,
+    /// All other capabilities, including experimental ones, by name
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
+}
+
+/// The room versions a homeserver supports
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoomVersions {
+    /// The version new rooms are created with, e. g. `10`
+    pub default: String,
+    /// All supported versions and their stability
+    pub available: HashMap<String, RoomVersionStability>,
+}
+
+impl RoomVersions {
+    /// Returns whether rooms of the given version can be created and joined
+    pub fn supports(&self, version: &str) -> bool {
+        self.available.contains_key(version)
+    }
+}
+
+/// Whether a room version is considered stable
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomVersionStability {
+    /// The version is part of the specification
+    Stable,
+    /// The version is experimental and may change
+    Unstable,
+}
+
+#[derive(Deserialize, Debug)]
+struct CapabilitiesInfo {
+    capabilities: Capabilities,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Reads a capability of the form `{ "enabled": true }`
+fn boolean_capability<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    struct BooleanCapability {
+        enabled: bool,
+    }
+
+    serde::Deserialize::deserialize(deserializer)
+        .map(|capability: BooleanCapability| capability.enabled)
 }
 
 pub struct HomeserverBuilder<Username, Password, AccessToken> {
     server: String,
+    api_version: ApiVersion,
     username: Username,
     password: Password,
     access_token: AccessToken,
+    device_id: Option<String>,
+    initial_device_display_name: Option<String>,
+    refresh_token: Option<String>,
+    access_token_in_query: bool,
+    retry_policy: RetryPolicy,
+    store: Arc<dyn StateStore>,
+    http: HttpConfig,
+    on_session_refresh: Option<SessionCallback>,
+    login_info: Option<LoginInfo>,
 }
 
 /// Represents a Matrix homeserver to which an access token has been created
+///
+/// The methods block until the request has finished. They run the
+/// implementation of `AsyncHomeserver` on a background thread, which is
+/// shared with the rooms created from this object.
 pub struct Homeserver {
-    client: Rc<reqwest::Client>,
+    reactor: Arc<Reactor>,
+    /// The options of the HTTP client, see `into_async`
+    #[cfg(feature = "async")]
+    http: HttpConfig,
     info: ServerInfo,
+    login_info: Option<LoginInfo>,
 }
 
 /// Represents a Matrix room from which events can be fetched from
+///
+/// Rooms can be moved to other threads, e. g. to handle each room on its own
+/// thread, and shared between threads with an `Arc`.
+/// Like `Homeserver`, they run the implementation of `AsyncRoom` on a
+/// background thread.
 pub struct Room {
-    id: String,
-    latest_since: Option<String>,
-    client: Rc<reqwest::Client>,
+    id: RoomId,
+    latest_since: Mutex<Option<String>>,
+    /// Whether the room is read without having joined it
+    peeking: bool,
+    reactor: Arc<Reactor>,
     info: ServerInfo,
 }
 
+// `Homeserver` and `Room` can be moved to and shared between threads
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn is_send_sync<T: Send + Sync>() {}
+    is_send_sync::<Homeserver>();
+    is_send_sync::<Room>();
+}
+
 /// A message received from or to be sent to a room
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     /// Text message. Should not be used to reply to messages!
     Text(String),
@@ -82,16 +743,16 @@ pub enum Message {
     /// Notice. Should be used for automatic replies. Should not be replied to!
     Notice(String),
     /// Image file. The URL should be created by uploading to the homesever.
-    Image { body: String, url: String },
+    Image { body: String, url: MxcUri },
     /// File. The URL should be created by uploading to the homesever.
-    File { body: String, url: String },
+    File { body: String, url: MxcUri },
     /// Location. `geo_uri` should be a Geo URI.
     /// E. g. `geo:37.786971,-122.399677`.
     Location { body: String, geo_uri: String },
     /// Video file. The URL should be created by uploading to the homesever.
-    Video { body: String, url: String },
+    Video { body: String, url: MxcUri },
     /// Audio file. The URL should be created by uploading to the homesever.
-    Audio { body: String, url: String },
+    Audio { body: String, url: MxcUri },
 }
 
 /// An event received from or to be sent to a room
@@ -103,7 +764,13 @@ pub enum RoomEvent {
     /// The topice of the room.
     Topic(String),
     /// The avatar (an image) of the room.
-    Avatar { url: String },
+    Avatar { url: MxcUri },
+    /// The membership, display name or avatar of a member.
+    ///
+    /// Member events are lazy loaded: besides changes, the current member
+    /// event of the sender of a message is returned before the message if
+    /// it has not been returned before.
+    Member(Member),
 }
 
 impl HomeserverBuilder<(), (), ()> {
@@ -111,280 +778,1733 @@ impl HomeserverBuilder<(), (), ()> {
     pub fn access_token(self, access_token: &str) -> HomeserverBuilder<(), (), String> {
         HomeserverBuilder {
             server: self.server,
+            api_version: self.api_version,
             username: self.username,
             password: self.password,
             access_token: access_token.to_owned(),
+            device_id: self.device_id,
+            initial_device_display_name: self.initial_device_display_name,
+            refresh_token: None,
+            access_token_in_query: self.access_token_in_query,
+            retry_policy: self.retry_policy,
+            store: self.store,
+            http: self.http,
+            on_session_refresh: self.on_session_refresh,
+            login_info: None,
         }
     }
 }
 
-impl<T> HomeserverBuilder<(), T, ()> {
-    /// Set the username
-    pub fn username(self, username: &str) -> HomeserverBuilder<String, T, ()> {
-        HomeserverBuilder {
-            server: self.server,
-            username: username.to_owned(),
-            password: self.password,
-            access_token: (),
-        }
+impl<U, P, A> HomeserverBuilder<U, P, A> {
+    /// Set the device ID to log in with
+    ///
+    /// If the device ID is already known to the homeserver, the device is
+    /// reused. Otherwise, a new device with that ID is created.
+    /// If no device ID is set, the homeserver generates one.
+    pub fn device_id(mut self, device_id: &str) -> Self {
+        self.device_id = Some(device_id.to_owned());
+        self
     }
-}
 
-impl<T> HomeserverBuilder<T, (), ()> {
-    /// Set the password
-    pub fn password(self, password: &str) -> HomeserverBuilder<T, String, ()> {
-        HomeserverBuilder {
-            server: self.server,
-            username: self.username,
-            password: password.to_owned(),
-            access_token: (),
-        }
+    /// Set the display name for the device created on login
+    ///
+    /// This is the name shown to users in their list of sessions.
+    /// It is ignored if the device already exists.
+    pub fn initial_device_display_name(mut self, display_name: &str) -> Self {
+        self.initial_device_display_name = Some(display_name.to_owned());
+        self
     }
-}
 
-impl HomeserverBuilder<String, String, ()> {
-    /// Log in with the given credentials
+    /// Set a callback that is called whenever the access token is refreshed
     ///
-    /// Create a new Homeserver object from username an password combination
+    /// Use this to persist the new access token and refresh token, see
+    /// `Session`.
+    /// The callback runs on the thread driving the requests, so it must not
+    /// call blocking methods of the homeserver object or its rooms.
+    pub fn on_session_refresh<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Session) + Send + Sync + 'static,
+    {
+        self.on_session_refresh = Some(Arc::new(callback));
+        self
+    }
+
+    /// Send the access token as `access_token` query parameter instead of an
+    /// `Authorization` header
     ///
-    /// This does not create a stateful connection.
-    /// It only constructs a `request` object and saves the URL of the Homeserver.
+    /// Only use this for legacy homeservers that do not accept the header:
+    /// Query parameters end up in the logs of proxies and servers, leaking the
+    /// access token.
+    pub fn access_token_in_query(mut self) -> Self {
+        self.access_token_in_query = true;
+        self
+    }
+
+    /// Set how requests that failed for transient reasons are retried
     ///
-    /// * `server_name` – The homeserver URL without trailing slash, e. g. `https://matrix.org`
-    /// * `username` – The username without homeserver part, e. g. `bot`
-    /// * `password` – The password
+    /// Requests are retried if the homeserver rate limits them. Requests that
+    /// can safely be sent twice, like sending a message with its transaction
+    /// ID, are also retried if the homeserver fails with a server error or
+    /// cannot be reached. By default, a request is retried up to 3 times, see
+    /// `RetryPolicy::default`.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set where state like the outboxes of rooms is kept
     ///
-    /// # Panics
-    /// If the server does not support or allow simple username and password
-    /// login, this function panics.
-    pub fn login(self) -> HomeserverBuilder<String, String, String> {
-        let at_info: AccesstokenInfo = {
-            let client = reqwest::Client::new();
-
-            let mut res = client
-                .get(&format!("{}/_matrix/client/r0/login", self.server))
-                .send()
-                .unwrap();
-
-            let mut pwlogin: bool = false;
-
-            let v: Value = serde_json::from_str(&(res.text().unwrap())).unwrap();
-            match v["flows"].as_array() {
-                Some(flowlist) => for flow in flowlist {
-                    match flow["type"].as_str() {
-                        Some("m.login.password") => {
-                            println!("Found login option `m.login.password`");
-                            pwlogin = true;
-                        }
-                        _ => (),
-                    }
-                },
-                _ => (),
-            }
-            if !pwlogin {
-                panic!("Server does not offer the login option `m.login.password`")
-            }
+    /// By default, the state is kept in memory only. Use a `FileStore` to keep
+    /// queued messages across restarts.
+    /// The store is cleared on logout.
+    pub fn state_store<S: StateStore + 'static>(mut self, store: S) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    /// Send all requests through the given proxy
+    ///
+    /// Can be called several times; the first proxy that intercepts a request
+    /// is used.
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.http.add_proxy(proxy);
+        self
+    }
 
-            let mut map: HashMap<&str, &str> = HashMap::new();
+    /// Trust the given DER encoded root certificate in addition to the
+    /// system's ones
+    ///
+    /// Use this for homeservers with a certificate signed by a private
+    /// certificate authority.
+    /// Fails if the certificate cannot be parsed.
+    pub fn add_root_certificate_der(mut self, der: &[u8]) -> Result<Self, Error> {
+        self.http.add_root_certificate_der(der)?;
+        Ok(self)
+    }
 
-            map.insert("type", "m.login.password");
-            map.insert("user", &self.username);
-            map.insert("password", &self.password);
+    /// Trust the given PEM encoded root certificate in addition to the
+    /// system's ones, see `add_root_certificate_der`
+    pub fn add_root_certificate_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        self.http.add_root_certificate_pem(pem)?;
+        Ok(self)
+    }
 
-            let mut res = client
-                .post(&format!("{}/_matrix/client/r0/login", self.server))
-                .json(&map)
-                .send()
-                .unwrap();
+    /// Set the timeout of a single request
+    ///
+    /// By default, requests time out after 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.http.set_timeout(timeout);
+        self
+    }
 
-            //println!("{}",res.text().unwrap());
-            res.json().unwrap()
-        };
+    /// Set the `User-Agent` header sent with all requests
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.http.set_user_agent(user_agent);
+        self
+    }
 
-        HomeserverBuilder {
-            server: self.server,
-            username: self.username,
-            password: self.password,
-            access_token: at_info.access_token,
-        }
+    /// Use preconfigured HTTP clients for all requests
+    ///
+    /// `client` is called with the handle of the reactor the requests run
+    /// on. Even the blocking API runs its requests on a reactor, see
+    /// `Homeserver`, so a blocking `reqwest::Client` cannot be used.
+    /// The options `proxy`, `add_root_certificate_der`,
+    /// `add_root_certificate_pem`, `timeout` and `user_agent` are ignored
+    /// then.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let server = Homeserver::new("https://matrix.example.org")
+    ///     .http_client(|handle| {
+    ///         let mut builder = reqwest::unstable::async::Client::builder();
+    ///         builder.proxy(reqwest::Proxy::all("http://proxy.example.org:8080").unwrap());
+    ///         builder.build(handle).expect("Building the HTTP client failed")
+    ///     })
+    ///     .access_token(access_token)
+    ///     .connect();
+    /// ```
+    pub fn http_client<F>(mut self, client: F) -> Self
+    where
+        F: Fn(&Handle) -> async::Client + Send + Sync + 'static,
+    {
+        self.http.set_client(Arc::new(client));
+        self
     }
 }
 
-impl<T, R> HomeserverBuilder<T, R, String> {
-    pub fn connect(self) -> Homeserver {
-        Homeserver {
-            client: Rc::new(reqwest::Client::new()),
-            info: ServerInfo {
-                server_name: self.server,
-                access_token: self.access_token,
-            },
-        }
+/// What is needed to send requests without access token, e. g. to log in
+#[derive(Clone)]
+struct Endpoint {
+    server: String,
+    api_version: ApiVersion,
+    retry_policy: RetryPolicy,
+}
+
+impl Endpoint {
+    /// Makes an unauthenticated call
+    fn call<R, T>(
+        &self,
+        client: &async::Client,
+        handle: &Handle,
+        call: Call<R, T>,
+    ) -> AsyncResult<T>
+    where
+        R: DeserializeOwned + 'static,
+        T: 'static,
+    {
+        let request = call.request.execute(
+            client,
+            handle,
+            &self.server,
+            &self.api_version,
+            Auth::None,
+            &self.retry_policy,
+        );
+        Box::new(request.then(call.response))
     }
 }
 
-impl Homeserver {
-    /// Start creating a new Homeserver object
-    ///
-    /// This does not create a stateful connection.
-    /// It only constructs a `request` object and saves the URL of the Homeserver.
+impl<U, P, A> HomeserverBuilder<U, P, A> {
+    /// Get all login methods the homeserver offers
     ///
-    /// * `server_name` – The homeserver URL without trailing slash, e. g. `https://matrix.org`
-    /// * `access_token` – The access token
-    pub fn new(server_url: &str) -> HomeserverBuilder<(), (), ()> {
-        HomeserverBuilder {
-            server: server_url.to_owned(),
-            username: (),
-            password: (),
-            access_token: (),
-        }
+    /// If the request fails, an empty list is returned.
+    pub fn login_flows(&self) -> Vec<LoginFlow> {
+        self.call(login_flows_call).unwrap_or_default()
     }
 
-    /// Create a new Homeserver object
-    ///
-    /// This does not create a stateful connection.
-    /// It only constructs a `request` object and saves the URL of the Homeserver.
+    /// Get all login methods the homeserver offers without blocking
     ///
-    /// * `server_name` – The homeserver URL without trailing slash, e. g. `https://matrix.org`
-    /// * `access_token` – The access token
-    pub fn connect(server_url: &str, access_token: &str) -> Self {
-        Self::new(server_url).access_token(access_token).connect()
+    /// Unlike `login_flows`, failures are reported as `Error`.
+    #[cfg(feature = "async")]
+    pub fn login_flows_async(&self, handle: &Handle) -> AsyncResult<Vec<LoginFlow>> {
+        self.call_async(handle, login_flows_call())
     }
 
-    /// Create a new Homeserver object from username an password combination
-    ///
-    /// This does not create a stateful connection.
-    /// It only constructs a `request` object and saves the URL of the Homeserver.
-    ///
-    /// * `server_name` – The homeserver URL without trailing slash, e. g. `https://matrix.org`
-    /// * `username` – The username without homeserver part, e. g. `bot`
-    /// * `password` – The password
-    ///
-    /// # Panics
-    /// If the server does not support or allow simple username and password
-    /// login, this function panics.
-    pub fn login_and_connect(server_url: &str, username: &str, password: &str) -> Self {
-        Self::new(server_url)
-            .username(username)
-            .password(password)
-            .login()
-            .connect()
+    /// Get the versions of the client-server API the homeserver supports
+    pub fn versions(&self) -> Result<Versions, Error> {
+        self.call(versions_call)
     }
 
-    /// Returns the access token
-    ///
-    /// This is especially usefull, if you authenticated via username and
-    /// password and want to retrieve the access token for later use.
-    pub fn get_access_token(&self) -> String {
-        return self.info.access_token.clone();
+    /// Like `versions`, but without blocking
+    #[cfg(feature = "async")]
+    pub fn versions_async(&self, handle: &Handle) -> AsyncResult<Versions> {
+        self.call_async(handle, versions_call())
     }
 
-    /// Creates a Matrix room object
-    ///
-    /// This joins the room.
-    /// If the room has already been joined, this function can be called anyway
-    /// to only create the room object.
+    /// Returns whether the homeserver offers the given login type,
+    /// e. g. `m.login.password`
+    pub fn supports_login(&self, login_type: &str) -> bool {
+        offers_login(&self.login_flows(), login_type)
+    }
+
+    /// Like `supports_login`, but without blocking
     ///
-    /// If the room cannot be joined, `None` is returned
-    pub fn join_room(&self, room_name: String) -> Option<Room> {
-        let map: HashMap<String, String> = HashMap::new();
-
-        let mut res = self
-            .client
-            .post(&format!(
-                "{}{}{}{}{}",
-                self.info.server_name,
-                "/_matrix/client/r0/join/",
-                utf8_percent_encode(&room_name, PATH_SEGMENT_ENCODE_SET).to_string(),
-                "?access_token=",
-                utf8_percent_encode(&self.info.access_token, ACCESS_TOKEN_ENCODE_SET).to_string()
-            )).json(&map)
-            .send()
-            .unwrap();
-        let info: Result<JoinInfo, _> = res.json();
-        match info {
-            Ok(info) => Some(Room {
-                id: info.room_id,
-                latest_since: None,
-                client: self.client.clone(),
-                info: self.info.clone(),
-            }),
-            _ => None,
-        }
+    /// Unlike `supports_login`, failures are reported as `Error`.
+    #[cfg(feature = "async")]
+    pub fn supports_login_async(&self, login_type: &str, handle: &Handle) -> AsyncResult<bool> {
+        let login_type = login_type.to_owned();
+        Box::new(
+            self.login_flows_async(handle)
+                .map(move |flows| offers_login(&flows, &login_type)),
+        )
     }
 
-    /// Creates a new Matrix room on the server and returns a Matrix room object
+    /// Returns the URL the user has to open in a browser to log in via
+    /// single sign-on
     ///
-    /// The room will be created with the preset `public_chat`.
-    /// Thus, everyone can join the room.
+    /// After a successful login, the homeserver redirects the browser to
+    /// `redirect_url` with an additional query parameter `loginToken`.
+    /// This token can be used with `login_token`.
     ///
-    /// If the room cannot be created or already exists, `None` is returned.
-    pub fn create_room(&self, room_name: String) -> Option<Room> {
-        let mut map: HashMap<String, String> = HashMap::new();
-
-        map.insert("room_alias_name".to_owned(), room_name);
-        map.insert("preset".to_owned(), "public_chat".to_owned());
-
-        let mut res = self
-            .client
-            .post(&format!(
-                "{}/_matrix/client/r0/createRoom?access_token={}",
-                self.info.server_name,
-                utf8_percent_encode(&self.info.access_token, ACCESS_TOKEN_ENCODE_SET).to_string()
-            )).json(&map)
-            .send()
-            .unwrap();
-        let info: Result<JoinInfo, _> = res.json();
-        match info {
-            Ok(info) => Some(Room {
-                id: info.room_id,
-                latest_since: None,
-                client: self.client.clone(),
-                info: self.info.clone(),
-            }),
-            _ => None,
+    /// * `identity_provider` – The ID of the identity provider to use, if the
+    ///   server offers several. See `login_flows`.
+    /// * `redirect_url` – The URL to redirect to after login
+    pub fn sso_redirect_url(&self, identity_provider: Option<&str>, redirect_url: &str) -> String {
+        let request = match identity_provider {
+            Some(idp) => Request::get(&["login", "sso", "redirect", idp]),
+            None => Request::get(&["login", "sso", "redirect"]),
+        };
+        request
+            .query("redirectUrl", redirect_url)
+            .url(&self.server, self.api_version.get())
+    }
+
+    fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            server: self.server.clone(),
+            api_version: self.api_version.clone(),
+            retry_policy: self.retry_policy,
         }
     }
 
-    /// Get all current invites from the server
+    /// Makes an unauthenticated call on the reactor of the blocking API and
+    /// waits for its result
     ///
-    /// Returns a list of all room, the bot has been invited to.
-    pub fn get_invites(&mut self) -> Vec<String> {
-        let res = self
-            .client
-            .get(&format!(
-                "{}/_matrix/client/r0/sync?filter={{\"room\":{{\"rooms\":[]}}}}&access_token={}",
-                self.info.server_name,
-                utf8_percent_encode(&self.info.access_token, ACCESS_TOKEN_ENCODE_SET).to_string()
-            )).send();
+    /// `call` is only invoked on the reactor thread, since calls cannot be
+    /// moved between threads.
+    fn call<R, T, F>(&self, call: F) -> Result<T, Error>
+    where
+        R: DeserializeOwned + 'static,
+        T: Send + 'static,
+        F: FnOnce() -> Call<R, T> + Send + 'static,
+    {
+        let endpoint = self.endpoint();
+        self.http
+            .reactor()
+            .run(move |client, handle| endpoint.call(client, handle, call()))
+    }
 
-        // println!("{}",&res.unwrap().text().unwrap());
-        // return
+    /// Makes an unauthenticated call on the reactor of `handle`
+    #[cfg(feature = "async")]
+    fn call_async<R, T>(&self, handle: &Handle, call: Call<R, T>) -> AsyncResult<T>
+    where
+        R: DeserializeOwned + 'static,
+        T: 'static,
+    {
+        self.endpoint()
+            .call(&self.http.async_client(handle), handle, call)
+    }
 
-        match res {
-            Ok(mut res) => {
-                let mut vec = Vec::new();
-
-                let v: Value = serde_json::from_str(&(res.text().unwrap())).unwrap();
-                match v["rooms"]["invite"].as_object() {
-                    Some(invitelist) => for (room, info) in invitelist {
-                        for event in info["invite_state"]["events"].as_array().unwrap() {
-                            if event["membership"].as_str() == Some("invite") {
-                                vec.push(room.to_owned());
-                            }
-                        }
-                    },
-                    _ => (),
+    /// Runs an operation consuming the builder on the reactor of the blocking
+    /// API and waits for its result
+    fn run<T, F>(self, operation: F) -> Result<T, Error>
+    where
+        Self: Send + 'static,
+        T: Send + 'static,
+        F: FnOnce(Self, &async::Client, &Handle) -> AsyncResult<T> + Send + 'static,
+    {
+        let reactor = self.http.reactor();
+        reactor.run(move |client, handle| operation(self, client, handle))
+    }
+
+    /// Runs an operation consuming the builder on the reactor of `handle`
+    #[cfg(feature = "async")]
+    fn run_async<T, F>(self, handle: &Handle, operation: F) -> AsyncResult<T>
+    where
+        F: FnOnce(Self, &async::Client, &Handle) -> AsyncResult<T>,
+    {
+        let client = self.http.async_client(handle);
+        operation(self, &client, handle)
+    }
+
+    /// Returns the body of a registration request
+    fn register_request(&self, username: Option<&str>, password: Option<&str>) -> RegisterRequest {
+        RegisterRequest {
+            username: username.map(str::to_owned),
+            password: password.map(str::to_owned),
+            device_id: self.device_id.clone(),
+            initial_device_display_name: self.initial_device_display_name.clone(),
+            refresh_token: true,
+            auth: None,
+        }
+    }
+
+    /// Keeps the access token of a login or registration
+    fn logged_in(self, login_info: LoginInfo) -> HomeserverBuilder<U, P, String> {
+        HomeserverBuilder {
+            server: self.server,
+            api_version: self.api_version,
+            username: self.username,
+            password: self.password,
+            access_token: login_info.access_token.clone(),
+            device_id: self.device_id,
+            initial_device_display_name: self.initial_device_display_name,
+            refresh_token: login_info.refresh_token.clone(),
+            access_token_in_query: self.access_token_in_query,
+            retry_policy: self.retry_policy,
+            store: self.store,
+            http: self.http,
+            on_session_refresh: self.on_session_refresh,
+            login_info: Some(login_info),
+        }
+    }
+}
+
+/// Returns whether the login type is one of the login flows
+fn offers_login(flows: &[LoginFlow], login_type: &str) -> bool {
+    flows.iter().any(|flow| flow.login_type == login_type)
+}
+
+impl HomeserverBuilder<(), (), ()> {
+    /// Log in with a login token
+    ///
+    /// Login tokens are short-lived tokens, usually obtained via single
+    /// sign-on. See `sso_redirect_url` and `login_sso`.
+    ///
+    /// If the server does not support token login or rejects the token, the
+    /// error of the homeserver is returned.
+    pub fn login_token(self, token: &str) -> Result<HomeserverBuilder<(), (), String>, Error> {
+        let token = token.to_owned();
+        self.run(move |builder, client, handle| builder.login_token_with(&token, client, handle))
+    }
+
+    /// Like `login_token`, but without blocking
+    #[cfg(feature = "async")]
+    pub fn login_token_async(
+        self,
+        token: &str,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), String>> {
+        self.run_async(handle, |builder, client, handle| {
+            builder.login_token_with(token, client, handle)
+        })
+    }
+
+    fn login_token_with(
+        self,
+        token: &str,
+        client: &async::Client,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), String>> {
+        let call = login_call(&LoginRequest {
+            login_type: "m.login.token",
+            identifier: None,
+            password: None,
+            token: Some(token),
+            device_id: self.device_id.as_deref(),
+            initial_device_display_name: self.initial_device_display_name.as_deref(),
+            refresh_token: true,
+        });
+        Box::new(
+            self.endpoint()
+                .call(client, handle, call)
+                .map(move |login_info| self.logged_in(login_info)),
+        )
+    }
+
+    /// Log in via single sign-on
+    ///
+    /// This is meant for command line tools: A small HTTP listener is started
+    /// on a random port on `127.0.0.1` and the SSO URL redirecting back to it
+    /// is passed to `open_url`, which should show the URL to the user or open
+    /// it in a browser.
+    /// This function then blocks until the browser has been redirected back
+    /// to the listener and logs in with the received login token.
+    ///
+    /// There is no non-blocking variant, since waiting for the user to finish
+    /// logging in in a browser is not something to drive from a reactor
+    /// shared with other accounts. Applications that do not block can send
+    /// the user to `sso_redirect_url`, receive the login token on their own
+    /// redirect URL and log in with `login_token_async`.
+    ///
+    /// * `identity_provider` – The ID of the identity provider to use, if the
+    ///   server offers several. See `login_flows`.
+    /// * `timeout` – How long to wait for the redirect at most
+    /// * `open_url` – Called with the URL the user has to open
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let server = Homeserver::new("https://matrix.example.org")
+    ///     .login_sso(None, Duration::from_secs(300), |url| {
+    ///         println!("Please open {} to log in", url)
+    ///     })?
+    ///     .connect();
+    /// ```
+    ///
+    /// If the local listener fails, `Error::SsoListener` is returned, with
+    /// the kind `io::ErrorKind::TimedOut` if the timeout expired. If the
+    /// server does not support single sign-on or token login, the error of
+    /// the homeserver is returned.
+    pub fn login_sso<F: FnOnce(&str)>(
+        self,
+        identity_provider: Option<&str>,
+        timeout: Duration,
+        open_url: F,
+    ) -> Result<HomeserverBuilder<(), (), String>, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(Error::SsoListener)?;
+        let port = listener.local_addr().map_err(Error::SsoListener)?.port();
+        let redirect_url = format!("http://127.0.0.1:{}/", port);
+
+        open_url(&self.sso_redirect_url(identity_provider, &redirect_url));
+
+        let deadline = Instant::now() + timeout;
+        let token = wait_for_login_token(&listener, deadline).map_err(Error::SsoListener)?;
+        self.login_token(&token)
+    }
+
+    /// Register a guest account
+    ///
+    /// Guest accounts do not need a username or password.
+    /// They can only use a limited set of endpoints, e. g. to peek into world
+    /// readable rooms, see `Homeserver::peek_room`.
+    pub fn register_guest(self) -> Result<HomeserverBuilder<(), (), String>, Error> {
+        self.run(Self::register_guest_with)
+    }
+
+    /// Like `register_guest`, but without blocking
+    #[cfg(feature = "async")]
+    pub fn register_guest_async(
+        self,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), String>> {
+        self.run_async(handle, Self::register_guest_with)
+    }
+
+    fn register_guest_with(
+        self,
+        client: &async::Client,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), String>> {
+        let call = Call::new(
+            Request::post(&["register"])
+                .query("kind", "guest")
+                .json(&self.register_request(None, None)),
+        );
+        Box::new(
+            self.endpoint()
+                .call(client, handle, call)
+                .map(move |login_info| self.logged_in(login_info)),
+        )
+    }
+
+    /// Register a new account
+    ///
+    /// User-interactive authentication is completed as far as possible with
+    /// the given options. The stage `m.login.dummy` is completed
+    /// automatically.
+    ///
+    /// * `username` – The localpart of the new user ID, e. g. `bot`
+    /// * `password` – The password of the new account
+    /// * `options` – The information needed for the authentication stages
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let options = RegistrationOptions {
+    ///     registration_token: Some("f00b4r".to_owned()),
+    ///     ..RegistrationOptions::default()
+    /// };
+    /// let server = Homeserver::new("https://matrix.example.org")
+    ///     .register("bot", "secret", options)?
+    ///     .connect();
+    /// ```
+    pub fn register(
+        self,
+        username: &str,
+        password: &str,
+        options: RegistrationOptions,
+    ) -> Result<HomeserverBuilder<(), (), String>, Error> {
+        let register = self.register_request(Some(username), Some(password));
+        self.run(move |builder, client, handle| {
+            builder.register_with(register, options, client, handle)
+        })
+    }
+
+    /// Like `register`, but without blocking
+    #[cfg(feature = "async")]
+    pub fn register_async(
+        self,
+        username: &str,
+        password: &str,
+        options: RegistrationOptions,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), String>> {
+        let register = self.register_request(Some(username), Some(password));
+        self.run_async(handle, move |builder, client, handle| {
+            builder.register_with(register, options, client, handle)
+        })
+    }
+
+    fn register_with(
+        self,
+        register: RegisterRequest,
+        options: RegistrationOptions,
+        client: &async::Client,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), String>> {
+        let credentials = UiaCredentials {
+            password: None,
+            registration_token: options.registration_token,
+            recaptcha_response: options.recaptcha_response,
+            accept_terms: options.accept_terms,
+        };
+
+        let endpoint = self.endpoint();
+        let client = client.clone();
+        let handle = handle.clone();
+        let send = move |api_version| {
+            let client = client.clone();
+            let server = endpoint.server.clone();
+            let register = register.clone();
+            let credentials = credentials.clone();
+            retry(endpoint.retry_policy, false, &handle, move || {
+                let client = client.clone();
+                let server = server.clone();
+                let register = register.clone();
+                uia::authenticate(credentials.clone(), move |auth| {
+                    Request::post(&["register"])
+                        .json(&RegisterRequest {
+                            auth: auth.cloned(),
+                            ..register.clone()
+                        })
+                        .send(&client, &server, api_version, &Auth::None)
+                })
+            })
+        };
+        Box::new(
+            with_fallback(&self.api_version, send)
+                .map(move |login_info| self.logged_in(login_info)),
+        )
+    }
+}
+
+/// Accepts connections on `listener` until a request carrying the query
+/// parameter `loginToken` arrives and returns the token
+///
+/// Fails with `io::ErrorKind::TimedOut` once `deadline` has passed, or if the
+/// listener cannot accept connections anymore.
+fn wait_for_login_token(listener: &TcpListener, deadline: Instant) -> io::Result<String> {
+    // Poll instead of blocking in `accept`, which has no timeout
+    listener.set_nonblocking(true)?;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no login token received before the timeout",
+            ));
+        }
+
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+
+        // Browsers may open connections they never send a request on, which
+        // must not hold up the one carrying the token
+        let read_timeout = cmp::min(deadline - now, Duration::from_secs(2));
+        if stream.set_nonblocking(false).is_err()
+            || stream.set_read_timeout(Some(read_timeout)).is_err()
+        {
+            continue;
+        }
+
+        let mut request_line = String::new();
+        if BufReader::new(&stream)
+            .read_line(&mut request_line)
+            .is_err()
+        {
+            continue;
+        }
+
+        // The request line looks like `GET /?loginToken=… HTTP/1.1`
+        let token = request_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|path| url::Url::parse(&format!("http://127.0.0.1{}", path)).ok())
+            .and_then(|url| {
+                url.query_pairs()
+                    .find(|(key, _)| key == "loginToken")
+                    .map(|(_, value)| value.into_owned())
+            });
+
+        match token {
+            Some(token) => {
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      Content-Type: text/plain; charset=utf-8\r\n\
+                      Connection: close\r\n\r\n\
+                      Login successful. You can close this window now.",
+                );
+                return Ok(token);
+            }
+            None => {
+                let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n");
+            }
+        }
+    }
+}
+
+impl<T> HomeserverBuilder<(), T, ()> {
+    /// Set the username
+    ///
+    /// * `username` – The username without homeserver part, e. g. `bot`, or
+    ///   the fully qualified user ID, e. g. `@bot:matrix.org`
+    pub fn username(self, username: &str) -> HomeserverBuilder<UserIdentifier, T, ()> {
+        self.identifier(UserIdentifier::User {
+            user: username.to_owned(),
+        })
+    }
+
+    /// Set the email address of the user
+    ///
+    /// The email address must have been associated with the account on the
+    /// homeserver.
+    pub fn email(self, address: &str) -> HomeserverBuilder<UserIdentifier, T, ()> {
+        self.identifier(UserIdentifier::ThirdParty {
+            medium: "email".to_owned(),
+            address: address.to_owned(),
+        })
+    }
+
+    /// Set the phone number of the user
+    ///
+    /// * `country` – The two-letter ISO-3166-1 alpha-2 country code, e. g. `DE`
+    /// * `phone` – The phone number as dialled from `country`
+    pub fn phone(self, country: &str, phone: &str) -> HomeserverBuilder<UserIdentifier, T, ()> {
+        self.identifier(UserIdentifier::Phone {
+            country: country.to_owned(),
+            phone: phone.to_owned(),
+        })
+    }
+
+    /// Set how the user is identified on login
+    pub fn identifier(
+        self,
+        identifier: UserIdentifier,
+    ) -> HomeserverBuilder<UserIdentifier, T, ()> {
+        HomeserverBuilder {
+            server: self.server,
+            api_version: self.api_version,
+            username: identifier,
+            password: self.password,
+            access_token: (),
+            device_id: self.device_id,
+            initial_device_display_name: self.initial_device_display_name,
+            refresh_token: None,
+            access_token_in_query: self.access_token_in_query,
+            retry_policy: self.retry_policy,
+            store: self.store,
+            http: self.http,
+            on_session_refresh: self.on_session_refresh,
+            login_info: None,
+        }
+    }
+}
+
+impl<T> HomeserverBuilder<T, (), ()> {
+    /// Set the password
+    pub fn password(self, password: &str) -> HomeserverBuilder<T, String, ()> {
+        HomeserverBuilder {
+            server: self.server,
+            api_version: self.api_version,
+            username: self.username,
+            password: password.to_owned(),
+            access_token: (),
+            device_id: self.device_id,
+            initial_device_display_name: self.initial_device_display_name,
+            refresh_token: None,
+            access_token_in_query: self.access_token_in_query,
+            retry_policy: self.retry_policy,
+            store: self.store,
+            http: self.http,
+            on_session_refresh: self.on_session_refresh,
+            login_info: None,
+        }
+    }
+}
+
+impl HomeserverBuilder<UserIdentifier, String, ()> {
+    /// Log in with the given credentials
+    ///
+    /// Create a new Homeserver object from username an password combination
+    ///
+    /// This does not create a stateful connection.
+    /// It only constructs a `request` object and saves the URL of the Homeserver.
+    ///
+    /// * `server_name` – The homeserver URL, e. g. `https://matrix.org`
+    /// * `username` – The username without homeserver part, e. g. `bot`
+    /// * `password` – The password
+    ///
+    /// The complete login response, including the user ID and device ID, is
+    /// kept and can be retrieved from the `Homeserver` object after
+    /// connecting.
+    ///
+    /// If the server does not offer simple username and password login,
+    /// `Error::UnsupportedLogin` is returned. If it rejects the credentials,
+    /// the error of the homeserver is returned.
+    pub fn login(self) -> Result<HomeserverBuilder<UserIdentifier, String, String>, Error> {
+        self.run(Self::login_with)
+    }
+
+    /// Like `login`, but without blocking
+    #[cfg(feature = "async")]
+    pub fn login_async(
+        self,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<UserIdentifier, String, String>> {
+        self.run_async(handle, Self::login_with)
+    }
+
+    fn login_with(
+        self,
+        client: &async::Client,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<UserIdentifier, String, String>> {
+        let endpoint = self.endpoint();
+        let client = client.clone();
+        let handle = handle.clone();
+        let flows = endpoint.call(&client, &handle, login_flows_call());
+        Box::new(flows.and_then(move |flows| -> AsyncResult<_> {
+            let password_login = "m.login.password";
+            if !offers_login(&flows, password_login) {
+                return fail(Error::UnsupportedLogin(password_login.to_owned()));
+            }
+
+            let call = login_call(&LoginRequest {
+                login_type: password_login,
+                identifier: Some(&self.username),
+                password: Some(&self.password),
+                token: None,
+                device_id: self.device_id.as_deref(),
+                initial_device_display_name: self.initial_device_display_name.as_deref(),
+                refresh_token: true,
+            });
+            Box::new(
+                endpoint
+                    .call(&client, &handle, call)
+                    .map(move |login_info| self.logged_in(login_info)),
+            )
+        }))
+    }
+}
+
+impl<T, R> HomeserverBuilder<T, R, String> {
+    /// Set the refresh token belonging to the access token
+    ///
+    /// This is only needed if the access token has been obtained by an
+    /// earlier login and has been stored together with its refresh token.
+    /// When logging in, the refresh token is set automatically.
+    pub fn refresh_token(mut self, refresh_token: &str) -> Self {
+        self.refresh_token = Some(refresh_token.to_owned());
+        self
+    }
+
+    pub fn connect(self) -> Homeserver {
+        let reactor = self.http.reactor();
+        #[cfg(feature = "async")]
+        let http = self.http.clone();
+        let (info, login_info) = self.into_server_info();
+        Homeserver {
+            reactor,
+            #[cfg(feature = "async")]
+            http,
+            info,
+            login_info,
+        }
+    }
+
+    fn into_server_info(self) -> (ServerInfo, Option<LoginInfo>) {
+        let info = ServerInfo {
+            server_name: self.server,
+            api_version: self.api_version,
+            session: Arc::new(Mutex::new(Session {
+                access_token: self.access_token,
+                refresh_token: self.refresh_token,
+                user_id: self.login_info.as_ref().map(|info| info.user_id.clone()),
+                device_id: self
+                    .login_info
+                    .as_ref()
+                    .and_then(|info| info.device_id.clone()),
+            })),
+            access_token_in_query: self.access_token_in_query,
+            retry_policy: self.retry_policy,
+            store: self.store,
+            outbox_locks: OutboxLocks::default(),
+            on_session_refresh: self.on_session_refresh,
+            refresh_lock: AsyncLock::default(),
+        };
+        (info, self.login_info)
+    }
+}
+
+impl Homeserver {
+    /// Start creating a new Homeserver object
+    ///
+    /// This does not create a stateful connection.
+    /// It only constructs a `request` object and saves the URL of the Homeserver.
+    ///
+    /// * `server_name` – The homeserver URL, e. g. `https://matrix.org`
+    /// * `access_token` – The access token
+    pub fn new(server_url: &str) -> HomeserverBuilder<(), (), ()> {
+        HomeserverBuilder {
+            server: server_url.trim_end_matches('/').to_owned(),
+            api_version: ApiVersion::new("v3"),
+            username: (),
+            password: (),
+            access_token: (),
+            device_id: None,
+            initial_device_display_name: None,
+            refresh_token: None,
+            access_token_in_query: false,
+            retry_policy: RetryPolicy::default(),
+            store: Arc::new(MemoryStore::new()),
+            http: HttpConfig::default(),
+            on_session_refresh: None,
+            login_info: None,
+        }
+    }
+
+    /// Start creating a new Homeserver object for the homeserver of a user
+    ///
+    /// The homeserver URL is looked up via `/.well-known/matrix/client` on the
+    /// server name. If there is no such file (status 404), the
+    /// server name itself is used. If it cannot be fetched, e. g. because the
+    /// connection fails, `Error::Discovery` is returned.
+    /// The homeserver is checked to actually be a Matrix homeserver, and the
+    /// newest API version supported by both the homeserver and this library
+    /// is chosen.
+    ///
+    /// * `server_name` – The server name, e. g. `matrix.org`. For a user ID,
+    ///   see `UserId::server_name`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let user_id: UserId = "@bot:example.org".parse()?;
+    /// let server = Homeserver::discover(&user_id.server_name())?
+    ///     .username(user_id.as_str())
+    ///     .password("secret")
+    ///     .login()?
+    ///     .connect();
+    /// ```
+    pub fn discover(server_name: &ServerName) -> Result<HomeserverBuilder<(), (), ()>, Error> {
+        Self::discover_blocking(server_name, HttpConfig::default())
+    }
+
+    /// Like `discover`, but sends all requests with clients built by the
+    /// given function
+    ///
+    /// Use this if the homeserver can only be reached via a proxy or with
+    /// custom root certificates. The function is kept for all further
+    /// requests, see `HomeserverBuilder::http_client`.
+    pub fn discover_with_client<F>(
+        server_name: &ServerName,
+        client: F,
+    ) -> Result<HomeserverBuilder<(), (), ()>, Error>
+    where
+        F: Fn(&Handle) -> async::Client + Send + Sync + 'static,
+    {
+        Self::discover_blocking(server_name, HttpConfig::with_client(Arc::new(client)))
+    }
+
+    /// Like `discover`, but without blocking
+    #[cfg(feature = "async")]
+    pub fn discover_async(
+        server_name: &ServerName,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), ()>> {
+        let http = HttpConfig::default();
+        let client = http.async_client(handle);
+        Self::discover_with(server_name.to_string(), http, &client, handle)
+    }
+
+    /// Like `discover_with_client`, but without blocking
+    #[cfg(feature = "async")]
+    pub fn discover_with_client_async<F>(
+        server_name: &ServerName,
+        client: F,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), ()>>
+    where
+        F: Fn(&Handle) -> async::Client + Send + Sync + 'static,
+    {
+        let http = HttpConfig::with_client(Arc::new(client));
+        let client = http.async_client(handle);
+        Self::discover_with(server_name.to_string(), http, &client, handle)
+    }
+
+    fn discover_blocking(
+        server_name: &ServerName,
+        http: HttpConfig,
+    ) -> Result<HomeserverBuilder<(), (), ()>, Error> {
+        let server_name = server_name.to_string();
+        let reactor = http.reactor();
+        reactor.run(move |client, handle| Self::discover_with(server_name, http, client, handle))
+    }
+
+    fn discover_with(
+        server_name: String,
+        http: HttpConfig,
+        client: &async::Client,
+        handle: &Handle,
+    ) -> AsyncResult<HomeserverBuilder<(), (), ()>> {
+        let well_known = client
+            .get(&format!(
+                "https://{}/.well-known/matrix/client",
+                server_name
+            ))
+            .send();
+        let base_url = well_known.then(move |res| -> AsyncResult<String> {
+            match res {
+                Ok(ref res) if res.status().as_u16() == 404 => {
+                    Box::new(future::ok(format!("https://{}", server_name)))
+                }
+                // Falling back to the server name could silently pick the wrong
+                // homeserver, e. g. if DNS or TLS is broken for the server name
+                Err(error) => fail(Error::Discovery(format!(
+                    "Fetching /.well-known/matrix/client failed: {}",
+                    error
+                ))),
+                Ok(mut res) => {
+                    if !res.status().is_success() {
+                        return fail(Error::Discovery(format!(
+                            "Fetching /.well-known/matrix/client failed with status {}",
+                            res.status()
+                        )));
+                    }
+                    Box::new(res.json::<DiscoveryInfo>().then(|res| match res {
+                        Ok(info) => Ok(info.homeserver.base_url),
+                        _ => Err(Error::Discovery(
+                            "Invalid /.well-known/matrix/client".to_owned(),
+                        )),
+                    }))
                 }
-                vec
             }
-            _ => Vec::new(),
+        });
+
+        let client = client.clone();
+        let handle = handle.clone();
+        Box::new(base_url.and_then(move |base_url| {
+            let builder = HomeserverBuilder {
+                http,
+                ..Self::new(&base_url)
+            };
+            let versions = builder.endpoint().call(&client, &handle, versions_call());
+            versions.then(move |res| match res {
+                Ok(versions) => {
+                    builder.api_version.set(versions.api_version());
+                    Ok(builder)
+                }
+                _ => Err(Error::Discovery(format!(
+                    "{} is not a Matrix homeserver",
+                    builder.server
+                ))),
+            })
+        }))
+    }
+
+    /// Create a new Homeserver object
+    ///
+    /// This does not create a stateful connection.
+    /// It only constructs a `request` object and saves the URL of the Homeserver.
+    ///
+    /// * `server_name` – The homeserver URL, e. g. `https://matrix.org`
+    /// * `access_token` – The access token
+    pub fn connect(server_url: &str, access_token: &str) -> Self {
+        Self::new(server_url).access_token(access_token).connect()
+    }
+
+    /// Create a new Homeserver object from username an password combination
+    ///
+    /// This does not create a stateful connection.
+    /// It only constructs a `request` object and saves the URL of the Homeserver.
+    ///
+    /// * `server_name` – The homeserver URL, e. g. `https://matrix.org`
+    /// * `username` – The username without homeserver part, e. g. `bot`
+    /// * `password` – The password
+    ///
+    /// Fails if the server does not offer simple username and password
+    /// login or rejects the credentials, see `HomeserverBuilder::login`.
+    pub fn login_and_connect(
+        server_url: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<Self, Error> {
+        Ok(Self::new(server_url)
+            .username(username)
+            .password(password)
+            .login()?
+            .connect())
+    }
+
+    /// Register a new account and create a new Homeserver object for it
+    ///
+    /// * `server_name` – The homeserver URL, e. g. `https://matrix.org`
+    /// * `username` – The localpart of the new user ID, e. g. `bot`
+    /// * `password` – The password of the new account
+    /// * `options` – The information needed for the authentication stages
+    pub fn register_and_connect(
+        server_url: &str,
+        username: &str,
+        password: &str,
+        options: RegistrationOptions,
+    ) -> Result<Self, Error> {
+        Ok(Self::new(server_url)
+            .register(username, password, options)?
+            .connect())
+    }
+
+    /// Register a guest account and create a new Homeserver object for it
+    ///
+    /// Guest accounts can only use a limited set of endpoints, e. g. to peek
+    /// into world readable rooms, see `peek_room`.
+    ///
+    /// * `server_name` – The homeserver URL, e. g. `https://matrix.org`
+    pub fn register_guest(server_url: &str) -> Result<Self, Error> {
+        Ok(Self::new(server_url).register_guest()?.connect())
+    }
+
+    /// Returns the access token
+    ///
+    /// This is especially usefull, if you authenticated via username and
+    /// password and want to retrieve the access token for later use.
+    pub fn get_access_token(&self) -> String {
+        return self.info.session.lock().unwrap().access_token.clone();
+    }
+
+    /// Returns the current session
+    ///
+    /// This contains the access token and, if the homeserver issued one, the
+    /// refresh token.
+    pub fn get_session(&self) -> Session {
+        self.info.session.lock().unwrap().clone()
+    }
+
+    /// Returns the fully qualified user ID of the logged in user
+    ///
+    /// The user ID is known if the access token has been created by logging
+    /// in with username and password or after `whoami` has been called.
+    /// Otherwise, `None` is returned.
+    pub fn get_user_id(&self) -> Option<UserId> {
+        self.info.session.lock().unwrap().user_id.clone()
+    }
+
+    /// Returns the device ID of the access token
+    ///
+    /// The device ID is known if the access token has been created by logging
+    /// in with username and password or after `whoami` has been called and
+    /// the server reported a device ID.
+    /// Otherwise, `None` is returned.
+    pub fn get_device_id(&self) -> Option<String> {
+        self.info.session.lock().unwrap().device_id.clone()
+    }
+
+    /// Returns the complete login response
+    ///
+    /// This is only available if the access token has been created by logging
+    /// in with username and password.
+    pub fn get_login_info(&self) -> Option<LoginInfo> {
+        self.login_info.clone()
+    }
+
+    /// Asks the homeserver who the owner of the access token is
+    ///
+    /// This is especially usefull, if you authenticated with an access token
+    /// and want to know the user ID of the bot, e. g. in order to ignore its
+    /// own messages.
+    /// The returned user ID and device ID are stored and can later be
+    /// retrieved with `get_user_id` and `get_device_id`.
+    ///
+    /// If the request fails, `None` is returned.
+    pub fn whoami(&mut self) -> Option<WhoAmI> {
+        self.run(|server| server.whoami()).ok()
+    }
+
+    /// Asks the homeserver what the user is allowed to do, e. g. whether the
+    /// password can be changed or which room versions are available
+    pub fn capabilities(&self) -> Result<Capabilities, Error> {
+        self.run(|server| server.capabilities())
+    }
+
+    /// Get the versions of the client-server API and the experimental
+    /// features the homeserver supports
+    pub fn versions(&self) -> Result<Versions, Error> {
+        self.run(|server| server.versions())
+    }
+
+    /// Invalidates the access token
+    ///
+    /// The `Homeserver` object is consumed, since it cannot be used anymore.
+    /// `Room` objects created from it will stop working as well.
+    /// The state store is cleared, including messages still in outboxes.
+    pub fn logout(self) -> Result<(), Error> {
+        self.run(AsyncHomeserver::logout)
+    }
+
+    /// Invalidates all access tokens of the user, including the one in use
+    ///
+    /// This logs out all devices of the user, e. g. when rotating credentials.
+    /// The `Homeserver` object is consumed, since it cannot be used anymore.
+    /// `Room` objects created from it will stop working as well.
+    /// The state store is cleared, including messages still in outboxes.
+    pub fn logout_all(self) -> Result<(), Error> {
+        self.run(AsyncHomeserver::logout_all)
+    }
+
+    /// Runs an operation of the non-blocking homeserver object on the reactor
+    /// and waits for its result
+    fn run<T, F>(&self, operation: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(AsyncHomeserver) -> AsyncResult<T> + Send + 'static,
+    {
+        let info = self.info.clone();
+        let login_info = self.login_info.clone();
+        self.reactor.run(move |client, handle| {
+            operation(AsyncHomeserver::new(client, handle, info, login_info))
+        })
+    }
+
+    /// Like `run`, for operations returning a room object
+    fn run_room<F>(&self, operation: F) -> Result<RoomState, Error>
+    where
+        F: FnOnce(AsyncHomeserver) -> AsyncResult<AsyncRoom> + Send + 'static,
+    {
+        self.run(move |server| Box::new(operation(server).map(|room| room.state())))
+    }
+
+    fn room(&self, state: RoomState) -> Room {
+        Room {
+            id: state.id,
+            latest_since: Mutex::new(state.latest_since),
+            peeking: state.peeking,
+            reactor: self.reactor.clone(),
+            info: self.info.clone(),
+        }
+    }
+
+    /// Creates a Matrix room object
+    ///
+    /// This joins the room.
+    /// If the room has already been joined, this function can be called anyway
+    /// to only create the room object.
+    ///
+    /// * `room` – The room ID or a room alias
+    ///
+    /// If the room cannot be joined, `None` is returned
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let alias: RoomAliasId = "#bottest:matrix.org".parse()?;
+    /// let room = server.join_room(alias);
+    /// ```
+    pub fn join_room<R: Into<RoomOrAliasId>>(&self, room: R) -> Option<Room> {
+        let room = room.into();
+        let state = self.run_room(move |server| server.join_room(room)).ok()?;
+        Some(self.room(state))
+    }
+
+    /// Creates a Matrix room object for a room without joining it
+    ///
+    /// This only works for rooms whose history is world readable.
+    /// Messages can be received from the room, but not sent to it.
+    /// Guest users, see `register_guest`, can only peek into rooms.
+    ///
+    /// * `room` – The room ID or a room alias
+    ///
+    /// If the room cannot be read, `None` is returned
+    pub fn peek_room<R: Into<RoomOrAliasId>>(&self, room: R) -> Option<Room> {
+        let room = room.into();
+        let state = self.run_room(move |server| server.peek_room(room)).ok()?;
+        // Without the end of the timeline, no events could be read
+        state.latest_since.as_ref()?;
+        Some(self.room(state))
+    }
+
+    /// Creates a new Matrix room on the server and returns a Matrix room object
+    ///
+    /// The room will be created with the preset `public_chat`.
+    /// Thus, everyone can join the room.
+    ///
+    /// If the room cannot be created or already exists, `None` is returned.
+    /// Use `create_room_with` for other options and to get the error.
+    pub fn create_room(&self, room_name: String) -> Option<Room> {
+        self.create_room_with(public_room_options(&room_name)).ok()
+    }
+
+    /// Creates a new Matrix room with the given options and returns a Matrix
+    /// room object
+    ///
+    /// If the options contain an alias that already exists,
+    /// `Error::RoomAliasInUse` is returned.
+    pub fn create_room_with(&self, options: CreateRoomBuilder) -> Result<Room, Error> {
+        let state = self.run_room(move |server| server.create_room_with(options))?;
+        Ok(self.room(state))
+    }
+
+    /// Get all current invites from the server
+    ///
+    /// Returns one invite per room, the bot has been invited to.
+    pub fn get_invites(&self) -> Result<Vec<Invite>, Error> {
+        self.run(|server| server.get_invites())
+    }
+
+    /// Declines the invite to a room
+    pub fn decline_invite(&self, room_id: &RoomId) -> Result<(), Error> {
+        let room_id = room_id.clone();
+        self.run(move |server| server.decline_invite(&room_id))
+    }
+
+    /// Joins all rooms whose invite is accepted by the policy
+    ///
+    /// If the policy declines other invites, they are declined as well.
+    /// Returns every invite together with its outcome: the joined room,
+    /// `None` if the invite has been declined or left pending, or the error
+    /// of joining or declining. Failed invites stay pending, so they are
+    /// handled again by the next call.
+    pub fn accept_invites(&self, policy: &InvitePolicy) -> Result<InviteOutcomes<Room>, Error> {
+        let policy = policy.clone();
+        let outcomes = self.run(move |server| {
+            Box::new(server.accept_invites(&policy).map(|outcomes| {
+                outcomes
+                    .into_iter()
+                    .map(|(invite, outcome)| {
+                        (invite, outcome.map(|room| room.map(|room| room.state())))
+                    })
+                    .collect::<Vec<_>>()
+            }))
+        })?;
+        Ok(outcomes
+            .into_iter()
+            .map(|(invite, outcome)| {
+                (
+                    invite,
+                    outcome.map(|state| state.map(|state| self.room(state))),
+                )
+            })
+            .collect())
+    }
+
+    /// Looks up the room a room alias points to
+    ///
+    /// Unlike `join_room`, this does not join the room.
+    pub fn resolve_alias(&self, alias: &RoomAliasId) -> Result<RoomAliasInfo, Error> {
+        let alias = alias.clone();
+        self.run(move |server| server.resolve_alias(&alias))
+    }
+
+    /// Creates a room alias pointing to the given room
+    ///
+    /// The alias must belong to the homeserver of the user. To show it in
+    /// clients, add it to the canonical aliases of the room as well, see
+    /// `Room::set_canonical_alias`.
+    pub fn create_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> Result<(), Error> {
+        let alias = alias.clone();
+        let room_id = room_id.clone();
+        self.run(move |server| server.create_alias(&alias, &room_id))
+    }
+
+    /// Deletes a room alias
+    ///
+    /// Usually, only the creator of an alias and room moderators may delete
+    /// it.
+    pub fn delete_alias(&self, alias: &RoomAliasId) -> Result<(), Error> {
+        let alias = alias.clone();
+        self.run(move |server| server.delete_alias(&alias))
+    }
+}
+
+/// Returns the call asking the homeserver who the owner of the access token
+/// is, which stores the user ID and device ID in `info`
+fn whoami_call(info: &ServerInfo) -> Call<WhoAmI, WhoAmI> {
+    let info = info.clone();
+    Call::new(Request::get(&["account", "whoami"])).map(move |whoami| {
+        info.store_whoami(&whoami);
+        whoami
+    })
+}
+
+fn capabilities_call() -> Call<CapabilitiesInfo, Capabilities> {
+    Call::new(Request::get(&["capabilities"])).map(|info: CapabilitiesInfo| info.capabilities)
+}
+
+fn versions_call() -> Call<Versions, Versions> {
+    Call::new(Request::get(&["versions"]).unversioned())
+}
+
+fn login_flows_call() -> Call<LoginFlows, Vec<LoginFlow>> {
+    Call::new(Request::get(&["login"])).map(|flows: LoginFlows| flows.flows)
+}
+
+fn login_call(request: &LoginRequest) -> Call<LoginInfo, LoginInfo> {
+    Call::new(Request::post(&["login"]).json(request))
+}
+
+/// Returns the call invalidating the access token, or all access tokens of
+/// the user if `all` is set
+fn logout_call(all: bool) -> Call<Value, ()> {
+    let request = if all {
+        Request::post(&["logout", "all"])
+    } else {
+        Request::post(&["logout"])
+    };
+    Call::new(request).map(|_| ())
+}
+
+/// Returns the call joining a room, which returns the room ID
+fn join_call(room: &RoomOrAliasId) -> Call<JoinInfo, RoomId> {
+    let map: HashMap<String, String> = HashMap::new();
+    let request = Request::post(&["join", room.as_str()])
+        .idempotent(true)
+        .json(&map);
+    Call::new(request).map(|info: JoinInfo| info.room_id)
+}
+
+/// Returns the call for the current end of a room's timeline
+///
+/// When peeking, this is remembered, so that only newer messages are
+/// returned by `get_new_messages`.
+fn timeline_end_call(room_id: &RoomId) -> Call<Value, Option<String>> {
+    let request = Request::get(&["rooms", room_id.as_str(), "messages"])
+        .query("dir", "b")
+        .query("limit", "1");
+    Call::new(request).map(|v: Value| v["start"].as_str().map(str::to_owned))
+}
+
+/// Returns the options of rooms created by `create_room`
+fn public_room_options(room_name: &str) -> CreateRoomBuilder {
+    CreateRoomBuilder::new()
+        .alias_name(room_name)
+        .preset(RoomPreset::PublicChat)
+}
+
+/// Returns the call creating a room, which returns the room ID
+///
+/// The error for an already existing alias is turned into
+/// `Error::RoomAliasInUse`.
+fn create_room_call(options: &CreateRoomBuilder) -> Call<JoinInfo, RoomId> {
+    let alias_name = options.get_alias_name().map(str::to_owned);
+    Call::new(Request::post(&["createRoom"]).json(options))
+        .map(|info: JoinInfo| info.room_id)
+        .map_err(|error| match alias_name {
+            Some(alias_name) if error.errcode() == Some("M_ROOM_IN_USE") => {
+                Error::RoomAliasInUse(alias_name)
+            }
+            _ => error,
+        })
+}
+
+/// Returns the call for the invites of the user with the given ID, see
+/// `invites_from_sync`
+fn invites_call(user_id: Option<UserId>) -> Call<Value, Vec<Invite>> {
+    Call::new(Request::get(&["sync"]).query("filter", r#"{"room":{"rooms":[]}}"#))
+        .map(move |v| invites_from_sync(&v, user_id.as_ref()))
+}
+
+/// The next sync token of a room, if the homeserver sent one, and the events
+/// up to it
+type NewEvents = (Option<String>, Vec<RoomEvent>);
+
+/// Returns the call for the events of a room after the sync token `since`
+///
+/// Rooms that are peeked into are not included in `/sync`, so their
+/// messages are paginated instead, starting at the end of the timeline when
+/// the room was peeked into. Without a sync token, there is nothing to
+/// paginate and `None` is returned.
+fn new_events_call(
+    room_id: &RoomId,
+    peeking: bool,
+    since: Option<&String>,
+) -> Option<Call<Value, NewEvents>> {
+    if !peeking {
+        let room_id = room_id.clone();
+        return Some(
+            Call::new(room_sync_request(&room_id, since)).map(move |v: Value| {
+                let next_batch = v["next_batch"].as_str().map(str::to_owned);
+                (next_batch, room_sync_events(&room_id, &v))
+            }),
+        );
+    }
+
+    let request = Request::get(&["rooms", room_id.as_str(), "messages"])
+        .query("dir", "f")
+        .query("from", since?);
+    Some(Call::new(request).map(|v: Value| {
+        let end = v["end"].as_str().map(str::to_owned);
+        (end, peeked_events(&v))
+    }))
+}
+
+/// Returns the sync request for new events in a room
+///
+/// The first sync of a room only fetches the sync token, but no events.
+fn room_sync_request(room_id: &RoomId, since: Option<&String>) -> Request {
+    match since {
+        None => Request::get(&["sync"]).query(
+            "filter",
+            &json!({
+                "room": {
+                    "rooms": [room_id],
+                    "state": {"lazy_load_members": true},
+                    "timeline": {"limit": 0},
+                },
+            })
+            .to_string(),
+        ),
+        Some(since) => Request::get(&["sync"]).query("since", since).query(
+            "filter",
+            &json!({"room": {"rooms": [room_id], "state": {"lazy_load_members": true}}})
+                .to_string(),
+        ),
+    }
+}
+
+/// Returns the events of a room from a sync response
+fn room_sync_events(room_id: &RoomId, v: &Value) -> Vec<RoomEvent> {
+    let mut vec = Vec::new();
+    let room = &v["rooms"]["join"][room_id.as_str()];
+
+    // The state before the timeline, which contains the lazy loaded members
+    if let Some(eventlist) = room["state"]["events"].as_array() {
+        vec.extend(
+            eventlist
+                .iter()
+                .filter_map(Member::from_event)
+                .map(RoomEvent::Member),
+        );
+    }
+
+    match room["timeline"]["events"].as_array() {
+        Some(eventlist) => for event in eventlist {
+            push_event(&mut vec, event);
+        },
+        _ => (),
+    }
+
+    vec
+}
+
+/// Returns the call for the members of a room
+///
+/// * `at` – The sync token the member list is returned for
+///
+/// The current list of joined members is fetched from `/joined_members`,
+/// which is cheaper for the homeserver.
+fn members_call(
+    room_id: &RoomId,
+    membership: Option<Membership>,
+    at: Option<&String>,
+) -> Call<Value, Vec<Member>> {
+    if membership == Some(Membership::Join) {
+        let request = Request::get(&["rooms", room_id.as_str(), "joined_members"]);
+        return Call::new(request).map(|v| joined_members(&v));
+    }
+
+    let mut request = Request::get(&["rooms", room_id.as_str(), "members"]);
+    if let Some(membership) = membership {
+        request = request.query("membership", membership.as_str());
+    }
+    if let Some(at) = at {
+        request = request.query("at", at);
+    }
+    Call::new(request).map(|v| members_from_chunk(&v))
+}
+
+fn member_call(room_id: &RoomId, user_id: &UserId) -> Call<MemberContent, Member> {
+    let request = Request::get(&[
+        "rooms",
+        room_id.as_str(),
+        "state",
+        "m.room.member",
+        user_id.as_str(),
+    ]);
+    let user_id = user_id.clone();
+    Call::new(request).map(move |content: MemberContent| content.into_member(user_id))
+}
+
+/// Returns the call sending a message, which returns the event ID
+fn send_message_call(room_id: &RoomId, message: Message, txn_id: &str) -> Call<EventInfo, EventId> {
+    let mut map: HashMap<String, String> = HashMap::new();
+
+    match message {
+        Message::Text(text) => {
+            map.insert("msgtype".to_owned(), "m.text".to_owned());
+            map.insert("body".to_owned(), text);
+        }
+        Message::Emote(text) => {
+            map.insert("msgtype".to_owned(), "m.emote".to_owned());
+            map.insert("body".to_owned(), text);
+        }
+        Message::Notice(text) => {
+            map.insert("msgtype".to_owned(), "m.notice".to_owned());
+            map.insert("body".to_owned(), text);
+        }
+        Message::Image { body, url } => {
+            map.insert("msgtype".to_owned(), "m.image".to_owned());
+            map.insert("body".to_owned(), body);
+            map.insert("url".to_owned(), url.into());
+        }
+        Message::File { body, url } => {
+            map.insert("msgtype".to_owned(), "m.file".to_owned());
+            map.insert("body".to_owned(), body);
+            map.insert("url".to_owned(), url.into());
+        }
+        Message::Location { body, geo_uri } => {
+            map.insert("msgtype".to_owned(), "m.location".to_owned());
+            map.insert("body".to_owned(), body);
+            map.insert("geo_uri".to_owned(), geo_uri);
+        }
+        Message::Audio { body, url } => {
+            map.insert("msgtype".to_owned(), "m.audio".to_owned());
+            map.insert("body".to_owned(), body);
+            map.insert("url".to_owned(), url.into());
+        }
+        Message::Video { body, url } => {
+            map.insert("msgtype".to_owned(), "m.video".to_owned());
+            map.insert("body".to_owned(), body);
+            map.insert("url".to_owned(), url.into());
+        }
+    }
+    let request =
+        Request::put(&["rooms", room_id.as_str(), "send", "m.room.message", txn_id]).json(&map);
+    Call::new(request).map(|info: EventInfo| info.event_id)
+}
+
+#[derive(Serialize)]
+struct MembershipRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<&'a UserId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a str>,
+}
+
+/// Returns the call for a membership change, e. g. `invite` or `leave`
+///
+/// `user_id` is the user whose membership is changed, if it is not the
+/// logged in user. If the homeserver refuses to change the membership of
+/// another user, `Error::InsufficientPowerLevel` is returned.
+fn membership_call(
+    room_id: &RoomId,
+    action: &str,
+    user_id: Option<&UserId>,
+    reason: Option<&str>,
+) -> Call<Value, ()> {
+    let other_user = user_id.is_some();
+    let request = Request::post(&["rooms", room_id.as_str(), action])
+        .json(&MembershipRequest { user_id, reason });
+    Call::new(request)
+        .map(|_| ())
+        .map_err(move |error| match error {
+            Error::Matrix(ref error) if other_user && error.errcode == "M_FORBIDDEN" => {
+                Error::InsufficientPowerLevel(error.error.clone())
+            }
+            error => error,
+        })
+}
+
+fn resolve_alias_call(alias: &RoomAliasId) -> Call<RoomAliasInfo, RoomAliasInfo> {
+    Call::new(Request::get(&["directory", "room", alias.as_str()]))
+}
+
+fn create_alias_call(alias: &RoomAliasId, room_id: &RoomId) -> Call<Value, ()> {
+    // Creating an alias that already exists fails
+    let request = Request::put(&["directory", "room", alias.as_str()])
+        .idempotent(false)
+        .json(&json!({ "room_id": room_id }));
+    Call::new(request).map(|_| ())
+}
+
+fn delete_alias_call(alias: &RoomAliasId) -> Call<Value, ()> {
+    Call::new(Request::delete(&["directory", "room", alias.as_str()])).map(|_| ())
+}
+
+fn aliases_call(room_id: &RoomId) -> Call<AliasesInfo, Vec<RoomAliasId>> {
+    Call::new(Request::get(&["rooms", room_id.as_str(), "aliases"]))
+        .map(|info: AliasesInfo| info.aliases)
+}
+
+/// Returns the call for the canonical alias event of a room
+///
+/// If the state event does not exist, the default content is returned.
+fn canonical_alias_call(room_id: &RoomId) -> Call<CanonicalAlias, CanonicalAlias> {
+    let request = Request::get(&["rooms", room_id.as_str(), "state", "m.room.canonical_alias"]);
+    Call::new(request).then(|res| match res {
+        Err(ref error) if error.errcode() == Some("M_NOT_FOUND") => Ok(CanonicalAlias::default()),
+        res => res,
+    })
+}
+
+fn set_canonical_alias_call(
+    room_id: &RoomId,
+    content: &CanonicalAlias,
+) -> Call<EventInfo, EventId> {
+    let request =
+        Request::put(&["rooms", room_id.as_str(), "state", "m.room.canonical_alias"]).json(content);
+    Call::new(request).map(|info: EventInfo| info.event_id)
+}
+
+/// Returns a link to an event, naming the servers of the room's creator and
+/// of the logged in user as `via` servers, if they are known
+fn event_permalink(info: &ServerInfo, room_id: &RoomId, event_id: &EventId) -> Permalink {
+    let mut permalink = Permalink::new(MatrixId::Event(room_id.clone().into(), event_id.clone()));
+    if let Some(server_name) = room_id.server_name() {
+        permalink = permalink.via(server_name);
+    }
+    if let Some(ref user_id) = info.session.lock().unwrap().user_id {
+        permalink = permalink.via(user_id.server_name());
+    }
+    permalink
+}
+
+/// Returns the events from the messages of a peeked room, see
+/// `new_events_call`
+fn peeked_events(v: &Value) -> Vec<RoomEvent> {
+    let mut vec = Vec::new();
+
+    if let Some(eventlist) = v["chunk"].as_array() {
+        for event in eventlist {
+            push_event(&mut vec, event);
         }
     }
+
+    vec
 }
 
 extern crate rand;
 
+/// Converts a timeline event to a `RoomEvent` and appends it to `events`
+///
+/// Events that are not understood or lack required fields are skipped.
+fn push_event(events: &mut Vec<RoomEvent>, event: &Value) {
+    events.extend(room_event(event));
+}
+
+fn room_event(event: &Value) -> Option<RoomEvent> {
+    if let Some(member) = Member::from_event(event) {
+        return Some(RoomEvent::Member(member));
+    }
+
+    let content = &event["content"];
+    let body = || content["body"].as_str().map(str::to_owned);
+    // Files without a valid mxc URI, e. g. encrypted ones, are skipped as well
+    let url = || {
+        content["url"]
+            .as_str()
+            .and_then(|url| MxcUri::parse(url).ok())
+    };
+    let message = match content["msgtype"].as_str()? {
+        "m.text" => Message::Text(body()?),
+        "m.emote" => Message::Emote(body()?),
+        "m.notice" => Message::Notice(body()?),
+        "m.image" => Message::Image {
+            body: body()?,
+            url: url()?,
+        },
+        "m.file" => Message::File {
+            body: body()?,
+            url: url()?,
+        },
+        "m.location" => Message::Location {
+            body: body()?,
+            geo_uri: content["geo_uri"].as_str()?.to_owned(),
+        },
+        "m.video" => Message::Video {
+            body: body()?,
+            url: url()?,
+        },
+        "m.audio" => Message::Audio {
+            body: body()?,
+            url: url()?,
+        },
+        _ => return None,
+    };
+    Some(RoomEvent::Message(message))
+}
+
 impl Room {
+    /// Returns the ID of the room
+    pub fn id(&self) -> &RoomId {
+        &self.id
+    }
+
     /// Receive all new events in a room since the last time this function has
     /// been called
     ///
@@ -398,76 +2518,52 @@ impl Room {
     ///
     /// # Examples
     ///
-    pub fn get_new_messages(&mut self) -> Vec<RoomEvent> {
-        let res = match self.latest_since.clone() {
-            None => self.client.get(
-                &format!("{}/_matrix/client/r0/sync?filter={{\"room\":{{\"rooms\":[\"{}\"],\"timeline\":{{\"limit\":0}}}}}}&access_token={}",
-                         self.info.server_name,
-                         utf8_percent_encode(&self.id, PATH_SEGMENT_ENCODE_SET).to_string(),
-                         utf8_percent_encode(&self.info.access_token, ACCESS_TOKEN_ENCODE_SET).to_string()))
-                .send(),
-            Some(since) => self.client.get(
-                &format!("{}/_matrix/client/r0/sync?since={}&filter={{\"room\":{{\"rooms\":[\"{}\"]}}}}&access_token={}",
-                         self.info.server_name,
-                         since,
-                         utf8_percent_encode(&self.id, PATH_SEGMENT_ENCODE_SET).to_string(),
-                         utf8_percent_encode(&self.info.access_token, ACCESS_TOKEN_ENCODE_SET).to_string()))
-                .send()
-        };
-
-        //println!("{}",&res.unwrap().text().unwrap());
-        //return Vec::new();
-
+    pub fn get_new_messages(&self) -> Vec<RoomEvent> {
+        // The sync token stays locked during the request, so that calls from
+        // several threads do not return the same events twice
+        let mut latest_since = self.latest_since.lock().unwrap();
+        let res = self.run_since(latest_since.clone(), |room| {
+            Box::new(
+                room.get_new_messages()
+                    .map(move |events| (room.state().latest_since, events)),
+            )
+        });
         match res {
-            Ok(mut res) => {
-                let mut vec = Vec::new();
-
-                let v: Value = serde_json::from_str(&(res.text().unwrap())).unwrap();
-                self.latest_since = Some(v["next_batch"].as_str().unwrap().to_owned());
-                match v["rooms"]["join"][&self.id]["timeline"]["events"].as_array() {
-                    Some(eventlist) => for event in eventlist {
-                        match event["content"]["msgtype"].as_str() {
-                            Some("m.text") => vec.push(RoomEvent::Message(Message::Text(
-                                event["content"]["body"].as_str().unwrap().to_owned(),
-                            ))),
-                            Some("m.emote") => vec.push(RoomEvent::Message(Message::Emote(
-                                event["content"]["body"].as_str().unwrap().to_owned(),
-                            ))),
-                            Some("m.notice") => vec.push(RoomEvent::Message(Message::Notice(
-                                event["content"]["body"].as_str().unwrap().to_owned(),
-                            ))),
-                            Some("m.image") => vec.push(RoomEvent::Message(Message::Image {
-                                body: event["content"]["body"].as_str().unwrap().to_owned(),
-                                url: event["content"]["url"].as_str().unwrap().to_owned(),
-                            })),
-                            Some("m.file") => vec.push(RoomEvent::Message(Message::File {
-                                body: event["content"]["body"].as_str().unwrap().to_owned(),
-                                url: event["content"]["url"].as_str().unwrap().to_owned(),
-                            })),
-                            Some("m.location") => vec.push(RoomEvent::Message(Message::Location {
-                                body: event["content"]["body"].as_str().unwrap().to_owned(),
-                                geo_uri: event["content"]["geo_uri"].as_str().unwrap().to_owned(),
-                            })),
-                            Some("m.video") => vec.push(RoomEvent::Message(Message::Audio {
-                                body: event["content"]["body"].as_str().unwrap().to_owned(),
-                                url: event["content"]["url"].as_str().unwrap().to_owned(),
-                            })),
-                            Some("m.audio") => vec.push(RoomEvent::Message(Message::Audio {
-                                body: event["content"]["body"].as_str().unwrap().to_owned(),
-                                url: event["content"]["url"].as_str().unwrap().to_owned(),
-                            })),
-                            _ => (),
-                        }
-                    },
-                    _ => (),
-                }
-
-                vec
+            Ok((since, events)) => {
+                *latest_since = since;
+                events
             }
             _ => Vec::new(),
         }
     }
 
+    /// Runs an operation of the non-blocking room object on the reactor and
+    /// waits for its result
+    fn run<T, F>(&self, operation: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(AsyncRoom) -> AsyncResult<T> + Send + 'static,
+    {
+        let latest_since = self.latest_since.lock().unwrap().clone();
+        self.run_since(latest_since, operation)
+    }
+
+    /// Like `run`, but with the given sync token
+    fn run_since<T, F>(&self, latest_since: Option<String>, operation: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(AsyncRoom) -> AsyncResult<T> + Send + 'static,
+    {
+        let state = RoomState {
+            id: self.id.clone(),
+            latest_since,
+            peeking: self.peeking,
+        };
+        let info = self.info.clone();
+        self.reactor
+            .run(move |client, handle| operation(AsyncRoom::new(state, client, handle, info)))
+    }
+
     /// Send a message to a room
     ///
     /// Note: To prevent prevent infinite-loop situations between bots, a bot
@@ -479,83 +2575,185 @@ impl Room {
     ///
     /// ```
     /// let message = Message::Notice("Hallo".to_owned());
-    /// room.send_message(message);
+    /// let event_id = room.send_message(message)?;
     /// ```
     ///
     /// ```
-    /// let logo_url = String::from("https://www.rust-lang.org/logos/rust-logo-128x128.png");
+    /// let logo_url: MxcUri = "mxc://matrix.org/SEsfnsuifSDFSSEF".parse()?;
     /// let message = Message::Image{body: "Rust Logo".to_owned(), url: logo_url};
-    /// room.send_message(message);
+    /// room.send_message(message)?;
     /// ```
-    pub fn send_message(&self, message: Message) {
-        let mut map: HashMap<String, String> = HashMap::new();
+    pub fn send_message(&self, message: Message) -> Result<EventId, Error> {
+        self.send_message_with_transaction_id(message, &Room::new_transaction_id())
+    }
 
-        match message {
-            Message::Text(text) => {
-                map.insert("msgtype".to_owned(), "m.text".to_owned());
-                map.insert("body".to_owned(), text);
-            }
-            Message::Emote(text) => {
-                map.insert("msgtype".to_owned(), "m.emote".to_owned());
-                map.insert("body".to_owned(), text);
-            }
-            Message::Notice(text) => {
-                map.insert("msgtype".to_owned(), "m.notice".to_owned());
-                map.insert("body".to_owned(), text);
-            }
-            Message::Image { body, url } => {
-                map.insert("msgtype".to_owned(), "m.image".to_owned());
-                map.insert("body".to_owned(), body);
-                map.insert("url".to_owned(), url);
-            }
-            Message::File { body, url } => {
-                map.insert("msgtype".to_owned(), "m.image".to_owned());
-                map.insert("body".to_owned(), body);
-                map.insert("url".to_owned(), url);
-            }
-            Message::Location { body, geo_uri } => {
-                map.insert("msgtype".to_owned(), "m.image".to_owned());
-                map.insert("body".to_owned(), body);
-                map.insert("geo_uri".to_owned(), geo_uri);
-            }
-            Message::Audio { body, url } => {
-                map.insert("msgtype".to_owned(), "m.image".to_owned());
-                map.insert("body".to_owned(), body);
-                map.insert("url".to_owned(), url);
-            }
-            Message::Video { body, url } => {
-                map.insert("msgtype".to_owned(), "m.image".to_owned());
-                map.insert("body".to_owned(), body);
-                map.insert("url".to_owned(), url);
-            }
-        }
-        self.client
-            .put(&format!(
-                "{}/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
-                self.info.server_name,
-                utf8_percent_encode(&self.id, PATH_SEGMENT_ENCODE_SET).to_string(),
-                rand::random::<u64>(),
-                utf8_percent_encode(&self.info.access_token, ACCESS_TOKEN_ENCODE_SET).to_string()
-            )).json(&map)
-            .send()
-            .unwrap();
+    /// Returns a new random transaction ID for `send_message_with_transaction_id`
+    pub fn new_transaction_id() -> String {
+        rand::random::<u64>().to_string()
+    }
+
+    /// Send a message to a room with the given transaction ID and return the
+    /// ID of the resulting event
+    ///
+    /// The homeserver sends a message only once per transaction ID. If it is
+    /// unclear whether sending a message succeeded, e. g. after a timeout,
+    /// call this again with the same transaction ID to avoid duplicates:
+    /// The event ID of the message is returned either way.
+    /// Requests retried automatically reuse the transaction ID as well.
+    ///
+    /// Transaction IDs are scoped to the access token. Use
+    /// `Room::new_transaction_id` to create one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let txn_id = Room::new_transaction_id();
+    /// let message = Message::Notice("Hallo".to_owned());
+    /// let event_id = match room.send_message_with_transaction_id(message.clone(), &txn_id) {
+    ///     Ok(event_id) => event_id,
+    ///     Err(_) => room.send_message_with_transaction_id(message, &txn_id)?,
+    /// };
+    /// ```
+    pub fn send_message_with_transaction_id(
+        &self,
+        message: Message,
+        txn_id: &str,
+    ) -> Result<EventId, Error> {
+        let txn_id = txn_id.to_owned();
+        self.run(move |room| room.send_message_with_transaction_id(message, &txn_id))
     }
 
     /// Invite someone to a room
     ///
+    /// Unlike sending a message, this does not return an event ID, since the
+    /// homeserver does not report the ID of the resulting membership event.
+    ///
     /// * `user_id` – The fully qualified user ID of the invitee.
-    pub fn invite(&self, user_id: &str) {
-        let mut map: HashMap<&str, &str> = HashMap::new();
-        map.insert("user_id", user_id);
-        self.client
-            .put(&format!(
-                "{}/_matrix/client/r0/rooms/{}/invite?access_token={}",
-                self.info.server_name,
-                utf8_percent_encode(&self.id, PATH_SEGMENT_ENCODE_SET).to_string(),
-                utf8_percent_encode(&self.info.access_token, ACCESS_TOKEN_ENCODE_SET).to_string()
-            )).json(&map)
-            .send()
-            .unwrap();
+    ///
+    /// If the user may not invite others, `Error::InsufficientPowerLevel` is
+    /// returned.
+    pub fn invite(&self, user_id: &UserId) -> Result<(), Error> {
+        let user_id = user_id.clone();
+        self.run(move |room| room.invite(&user_id))
+    }
+
+    /// Leave the room
+    ///
+    /// The room object can still be used to `forget` the room afterwards.
+    pub fn leave(&self) -> Result<(), Error> {
+        self.run(|room| room.leave())
+    }
+
+    /// Forget the room, so that it is no longer listed for the user
+    ///
+    /// The room has to be left first.
+    pub fn forget(&self) -> Result<(), Error> {
+        self.run(|room| room.forget())
+    }
+
+    /// Kick a user from the room
+    ///
+    /// The user can join again, unlike after `ban`.
+    /// If the user may not kick others or the other user has a power level as
+    /// high as the own one, `Error::InsufficientPowerLevel` is returned.
+    ///
+    /// * `reason` – The reason shown to the kicked user
+    pub fn kick(&self, user_id: &UserId, reason: Option<&str>) -> Result<(), Error> {
+        let user_id = user_id.clone();
+        let reason = reason.map(str::to_owned);
+        self.run(move |room| room.kick(&user_id, reason.as_deref()))
+    }
+
+    /// Ban a user from the room, kicking the user if necessary
+    ///
+    /// If the user may not ban others or the other user has a power level as
+    /// high as the own one, `Error::InsufficientPowerLevel` is returned.
+    ///
+    /// * `reason` – The reason shown to the banned user
+    pub fn ban(&self, user_id: &UserId, reason: Option<&str>) -> Result<(), Error> {
+        let user_id = user_id.clone();
+        let reason = reason.map(str::to_owned);
+        self.run(move |room| room.ban(&user_id, reason.as_deref()))
+    }
+
+    /// Lift the ban of a user
+    ///
+    /// The user is not invited again. If the user may not ban others,
+    /// `Error::InsufficientPowerLevel` is returned.
+    pub fn unban(&self, user_id: &UserId) -> Result<(), Error> {
+        let user_id = user_id.clone();
+        self.run(move |room| room.unban(&user_id))
+    }
+
+    /// Returns the aliases of this room on the homeserver of the user
+    ///
+    /// Aliases on other homeservers pointing to the room are not included.
+    pub fn aliases(&self) -> Result<Vec<RoomAliasId>, Error> {
+        self.run(|room| room.aliases())
+    }
+
+    /// Returns the members of the room
+    ///
+    /// * `membership` – Only return members with this membership, e. g.
+    ///   `Membership::Join`. If it is `None`, all users who have ever been a
+    ///   member are returned.
+    ///
+    /// The list matches the events returned by `get_new_messages` so far, so
+    /// it can be kept up to date with the `RoomEvent::Member` events it
+    /// returns later. Only the list of joined members is the current one.
+    pub fn members(&self, membership: Option<Membership>) -> Result<Vec<Member>, Error> {
+        self.run(move |room| room.members(membership))
+    }
+
+    /// Returns the membership, display name and avatar of a user in the room
+    ///
+    /// If the user has never been a member of the room, the homeserver
+    /// returns an error with the code `M_NOT_FOUND`.
+    pub fn member(&self, user_id: &UserId) -> Result<Member, Error> {
+        let user_id = user_id.clone();
+        self.run(move |room| room.member(&user_id))
+    }
+
+    /// Returns the aliases clients show for this room
+    ///
+    /// If none have been set, an empty `CanonicalAlias` is returned.
+    pub fn canonical_alias(&self) -> Result<CanonicalAlias, Error> {
+        self.run(|room| room.canonical_alias())
+    }
+
+    /// Sets the aliases clients show for this room
+    ///
+    /// All aliases must point to the room, otherwise the homeserver rejects
+    /// the change.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let alias: RoomAliasId = "#bots:example.org".parse()?;
+    /// server.create_alias(&alias, room.id())?;
+    /// let mut content = room.canonical_alias()?;
+    /// content.alt_aliases.push(alias);
+    /// room.set_canonical_alias(&content)?;
+    /// ```
+    pub fn set_canonical_alias(&self, content: &CanonicalAlias) -> Result<EventId, Error> {
+        let content = content.clone();
+        self.run(move |room| room.set_canonical_alias(&content))
+    }
+
+    /// Returns a link to an event in this room
+    ///
+    /// The link is displayed as matrix.to link, see `Permalink`. The server
+    /// of the logged in user is only named as `via` server if the user ID is
+    /// known, see `Homeserver::whoami`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let event_id = room.send_text("Hello".to_owned())?;
+    /// room.send_notice(format!("See {}", room.permalink(&event_id)))?;
+    /// ```
+    pub fn permalink(&self, event_id: &EventId) -> Permalink {
+        event_permalink(&self.info, &self.id, event_id)
     }
 
     /// Send a message of type `text` to a room
@@ -568,10 +2766,10 @@ impl Room {
     /// # Examples
     ///
     /// ```
-    /// room.send_text("Hallo".to_owned());
+    /// let event_id = room.send_text("Hallo".to_owned())?;
     /// ```
-    pub fn send_text(&self, text: String) {
-        self.send_message(Message::Text(text));
+    pub fn send_text(&self, text: String) -> Result<EventId, Error> {
+        self.send_message(Message::Text(text))
     }
     /// Send a message of type `emote` to a room
     ///
@@ -585,10 +2783,10 @@ impl Room {
     /// # Examples
     ///
     /// ```
-    /// room.send_emote("is having trouble".to_owned());
+    /// room.send_emote("is having trouble".to_owned())?;
     /// ```
-    pub fn send_emote(&self, text: String) {
-        self.send_message(Message::Emote(text));
+    pub fn send_emote(&self, text: String) -> Result<EventId, Error> {
+        self.send_message(Message::Emote(text))
     }
 
     /// Send a message of type `notice` to a room
@@ -601,9 +2799,122 @@ impl Room {
     /// # Examples
     ///
     /// ```
-    /// room.send_notice("Hallo".to_owned());
+    /// room.send_notice("Hallo".to_owned())?;
+    /// ```
+    pub fn send_notice(&self, text: String) -> Result<EventId, Error> {
+        self.send_message(Message::Notice(text))
+    }
+
+    /// Put a message into the outbox of the room and return its transaction
+    /// ID
+    ///
+    /// The message is not sent until `flush_outbox` is called. The outbox is
+    /// kept in the state store, see `HomeserverBuilder::state_store`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// room.enqueue(Message::Notice("Disk full".to_owned()))?;
+    /// // Retry until the homeserver is reachable again
+    /// while room.flush_outbox().is_err() {
+    ///     thread::sleep(Duration::from_secs(60));
+    /// }
     /// ```
-    pub fn send_notice(&self, text: String) {
-        self.send_message(Message::Notice(text));
+    pub fn enqueue(&self, message: Message) -> Result<String, Error> {
+        Ok(outbox::enqueue(&self.info, &self.id, message)?)
+    }
+
+    /// Get all messages in the outbox of the room, in the order they are sent
+    pub fn outbox(&self) -> Result<Vec<OutboxEntry>, Error> {
+        Ok(outbox::load(&self.info, &self.id)?)
+    }
+
+    /// Send all pending messages in the outbox of the room in order
+    ///
+    /// Each message is sent with its own transaction ID, so a message is never
+    /// delivered twice, even if it is sent again after an error.
+    /// If the homeserver rejects a message, e. g. because the bot is not
+    /// allowed to post, it is marked as failed and the next message is sent.
+    /// If the homeserver cannot be reached, sending stops and the error is
+    /// returned. The remaining messages stay pending, so that they are sent
+    /// in order by the next call.
+    /// Messages enqueued while sending are sent by the next call.
+    pub fn flush_outbox(&self) -> Result<(), Error> {
+        self.run(|room| room.flush_outbox())
+    }
+
+    /// Remove all sent and failed messages from the outbox of the room
+    pub fn prune_outbox(&self) -> Result<(), Error> {
+        Ok(outbox::prune(&self.info, &self.id)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the body `send_message_call` sends for the message
+    fn message_body(message: Message) -> Value {
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        send_message_call(&room_id, message, "txn")
+            .request
+            .body()
+            .unwrap()
+            .clone()
+    }
+
+    fn mxc_uri() -> MxcUri {
+        MxcUri::parse("mxc://example.org/media").unwrap()
+    }
+
+    #[test]
+    fn message_bodies() {
+        assert_eq!(
+            message_body(Message::Text("hi".to_owned())),
+            json!({"msgtype": "m.text", "body": "hi"})
+        );
+        assert_eq!(
+            message_body(Message::Emote("waves".to_owned())),
+            json!({"msgtype": "m.emote", "body": "waves"})
+        );
+        assert_eq!(
+            message_body(Message::Notice("ahoi!".to_owned())),
+            json!({"msgtype": "m.notice", "body": "ahoi!"})
+        );
+        assert_eq!(
+            message_body(Message::Image {
+                body: "cat.png".to_owned(),
+                url: mxc_uri(),
+            }),
+            json!({"msgtype": "m.image", "body": "cat.png", "url": "mxc://example.org/media"})
+        );
+        assert_eq!(
+            message_body(Message::File {
+                body: "notes.txt".to_owned(),
+                url: mxc_uri(),
+            }),
+            json!({"msgtype": "m.file", "body": "notes.txt", "url": "mxc://example.org/media"})
+        );
+        assert_eq!(
+            message_body(Message::Location {
+                body: "Berlin".to_owned(),
+                geo_uri: "geo:52.52,13.40".to_owned(),
+            }),
+            json!({"msgtype": "m.location", "body": "Berlin", "geo_uri": "geo:52.52,13.40"})
+        );
+        assert_eq!(
+            message_body(Message::Audio {
+                body: "song.ogg".to_owned(),
+                url: mxc_uri(),
+            }),
+            json!({"msgtype": "m.audio", "body": "song.ogg", "url": "mxc://example.org/media"})
+        );
+        assert_eq!(
+            message_body(Message::Video {
+                body: "clip.webm".to_owned(),
+                url: mxc_uri(),
+            }),
+            json!({"msgtype": "m.video", "body": "clip.webm", "url": "mxc://example.org/media"})
+        );
     }
 }
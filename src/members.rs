@@ -0,0 +1,106 @@
+//! Members of rooms
+//!
+//! See `Room::members` and `RoomEvent::Member`.
+
+use serde_json::{self, Value};
+
+use {MxcUri, UserId};
+
+/// The membership of a user in a room
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Membership {
+    Invite,
+    Join,
+    Knock,
+    Leave,
+    Ban,
+}
+
+impl Membership {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            Membership::Invite => "invite",
+            Membership::Join => "join",
+            Membership::Knock => "knock",
+            Membership::Leave => "leave",
+            Membership::Ban => "ban",
+        }
+    }
+}
+
+/// A user with a membership in a room, e. g. a joined or invited user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub user_id: UserId,
+    pub membership: Membership,
+    /// The display name of the user in this room
+    pub display_name: Option<String>,
+    /// The avatar of the user in this room
+    pub avatar_url: Option<MxcUri>,
+}
+
+/// The content of an `m.room.member` event
+#[derive(Deserialize)]
+pub(crate) struct MemberContent {
+    membership: Membership,
+    displayname: Option<String>,
+    avatar_url: Option<String>,
+}
+
+impl MemberContent {
+    pub(crate) fn into_member(self, user_id: UserId) -> Member {
+        Member {
+            user_id,
+            membership: self.membership,
+            display_name: self.displayname,
+            // Users without an avatar sometimes have an empty URL
+            avatar_url: self.avatar_url.and_then(|url| MxcUri::parse(&url).ok()),
+        }
+    }
+}
+
+impl Member {
+    /// Parses an `m.room.member` event
+    ///
+    /// Other events and member events with an unknown membership are
+    /// skipped.
+    pub(crate) fn from_event(event: &Value) -> Option<Self> {
+        if event["type"] != "m.room.member" {
+            return None;
+        }
+        let user_id = UserId::parse(event["state_key"].as_str()?).ok()?;
+        let content: MemberContent = serde_json::from_value(event["content"].clone()).ok()?;
+        Some(content.into_member(user_id))
+    }
+}
+
+/// Returns the members from a `/members` response
+pub(crate) fn members_from_chunk(v: &Value) -> Vec<Member> {
+    match v["chunk"].as_array() {
+        Some(events) => events.iter().filter_map(Member::from_event).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Returns the members from a `/joined_members` response
+pub(crate) fn joined_members(v: &Value) -> Vec<Member> {
+    let joined = match v["joined"].as_object() {
+        Some(joined) => joined,
+        None => return Vec::new(),
+    };
+
+    joined
+        .iter()
+        .filter_map(|(user_id, profile)| {
+            Some(Member {
+                user_id: UserId::parse(user_id).ok()?,
+                membership: Membership::Join,
+                display_name: profile["display_name"].as_str().map(str::to_owned),
+                avatar_url: profile["avatar_url"]
+                    .as_str()
+                    .and_then(|url| MxcUri::parse(url).ok()),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,164 @@
+//! Queue of messages waiting to be sent to a room
+//!
+//! Messages are put into the outbox of a room with `Room::enqueue` and sent
+//! in order with `Room::flush_outbox`. The outbox is kept in the state store,
+//! so that queued messages survive a restart when a persistent store like
+//! `FileStore` is used.
+//!
+//! Every change reads the outbox, modifies it and writes it back while
+//! holding the lock of the room in `OutboxLocks`, so that e. g. a message
+//! enqueued while `flush_outbox` is sending is not lost.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use serde_json;
+
+use error::{Error, MatrixError};
+use {EventId, Message, Room, RoomId, ServerInfo};
+
+/// Whether a queued message has been delivered
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// The message has not been sent yet
+    Pending,
+    /// The message has been sent and resulted in the given event
+    Sent(EventId),
+    /// The homeserver rejected the message with the given error message
+    ///
+    /// The message is not sent again.
+    Failed(String),
+}
+
+/// A message in the outbox of a room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxEntry {
+    /// The transaction ID the message is sent with
+    ///
+    /// It stays the same for all attempts, so the message is delivered at
+    /// most once.
+    pub transaction_id: String,
+    /// The message to send
+    pub message: Message,
+    /// Whether the message has been delivered
+    pub status: DeliveryStatus,
+}
+
+/// One lock per room, held while its outbox is changed
+///
+/// The locks are shared by a homeserver object and all its rooms.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OutboxLocks(Arc<Mutex<HashMap<RoomId, Arc<Mutex<()>>>>>);
+
+impl OutboxLocks {
+    /// Returns the lock of the given room
+    fn get(&self, room_id: &RoomId) -> Arc<Mutex<()>> {
+        let mut locks = self.0.lock().unwrap();
+        locks.entry(room_id.clone()).or_default().clone()
+    }
+}
+
+fn key(room_id: &RoomId) -> String {
+    format!("outbox/{}", room_id)
+}
+
+/// Reads the outbox of the given room from the store
+pub(crate) fn load(info: &ServerInfo, room_id: &RoomId) -> io::Result<Vec<OutboxEntry>> {
+    match info.store.get(&key(room_id))? {
+        Some(value) => Ok(serde_json::from_str(&value)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Writes the outbox of the given room to the store
+fn save(info: &ServerInfo, room_id: &RoomId, entries: &[OutboxEntry]) -> io::Result<()> {
+    if entries.is_empty() {
+        return info.store.remove(&key(room_id));
+    }
+    info.store
+        .set(&key(room_id), &serde_json::to_string(entries)?)
+}
+
+/// Reads the outbox of the given room, applies `change` and writes the
+/// outbox back, holding the lock of the room throughout
+fn update<T, F>(info: &ServerInfo, room_id: &RoomId, change: F) -> io::Result<T>
+where
+    F: FnOnce(&mut Vec<OutboxEntry>) -> T,
+{
+    let lock = info.outbox_locks.get(room_id);
+    let _locked = lock.lock().unwrap();
+    let mut entries = load(info, room_id)?;
+    let res = change(&mut entries);
+    save(info, room_id, &entries)?;
+    Ok(res)
+}
+
+/// Appends a message to the outbox of the given room and returns its
+/// transaction ID
+pub(crate) fn enqueue(info: &ServerInfo, room_id: &RoomId, message: Message) -> io::Result<String> {
+    let transaction_id = Room::new_transaction_id();
+    let entry = OutboxEntry {
+        transaction_id: transaction_id.clone(),
+        message,
+        status: DeliveryStatus::Pending,
+    };
+    update(info, room_id, |entries| entries.push(entry))?;
+    Ok(transaction_id)
+}
+
+/// Removes all sent and failed messages from the outbox of the given room
+pub(crate) fn prune(info: &ServerInfo, room_id: &RoomId) -> io::Result<()> {
+    update(info, room_id, |entries| {
+        entries.retain(|entry| entry.status == DeliveryStatus::Pending)
+    })
+}
+
+/// Returns the pending messages in the outbox of the given room, in order
+pub(crate) fn pending(info: &ServerInfo, room_id: &RoomId) -> io::Result<Vec<OutboxEntry>> {
+    let mut entries = load(info, room_id)?;
+    entries.retain(|entry| entry.status == DeliveryStatus::Pending);
+    Ok(entries)
+}
+
+/// Sets the status of the message with the given transaction ID
+///
+/// If the message has been removed from the outbox meanwhile, nothing
+/// happens.
+pub(crate) fn set_status(
+    info: &ServerInfo,
+    room_id: &RoomId,
+    transaction_id: &str,
+    status: DeliveryStatus,
+) -> io::Result<()> {
+    update(info, room_id, |entries| {
+        if let Some(entry) = entries
+            .iter_mut()
+            .find(|entry| entry.transaction_id == transaction_id)
+        {
+            entry.status = status;
+        }
+    })
+}
+
+/// Returns the status of a message after sending it
+///
+/// If the message should be sent again later, the error is returned instead.
+pub(crate) fn delivery_status(res: Result<EventId, Error>) -> Result<DeliveryStatus, Error> {
+    match res {
+        Ok(event_id) => Ok(DeliveryStatus::Sent(event_id)),
+        Err(Error::Matrix(ref error)) if is_rejection(error) => {
+            Ok(DeliveryStatus::Failed(error.error.clone()))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Returns whether the homeserver refused a request for good, as opposed to
+/// failing temporarily or asking for a new access token
+fn is_rejection(error: &MatrixError) -> bool {
+    error.status < 500
+        && error.errcode != "M_LIMIT_EXCEEDED"
+        && error.errcode != "M_UNKNOWN_TOKEN"
+        && error.errcode != "M_MISSING_TOKEN"
+}
@@ -0,0 +1,377 @@
+//! Links to users, rooms and events
+//!
+//! Matrix clients understand two kinds of links: `https://matrix.to/#/…`
+//! permalinks, which can also be opened in a browser, and `matrix:` URIs.
+//! Both are parsed into a `Permalink`, which can be turned into either kind.
+//!
+//! # Examples
+//!
+//! ```
+//! let link: Permalink = "https://matrix.to/#/%23rust%3Amatrix.org".parse()?;
+//! assert_eq!(link.matrix_uri(), "matrix:r/rust:matrix.org");
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use url::percent_encoding::{
+    percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET, USERINFO_ENCODE_SET,
+};
+
+use error::Error;
+use {EventId, RoomAliasId, RoomId, RoomOrAliasId, ServerName, UserId};
+
+const MATRIX_TO_PREFIX: &str = "https://matrix.to/#/";
+const MATRIX_URI_PREFIX: &str = "matrix:";
+
+define_encode_set! {
+    /// Characters encoded in identifiers of matrix.to links, like
+    /// `encodeURIComponent` in JavaScript does
+    pub MATRIX_TO_ENCODE_SET = [USERINFO_ENCODE_SET] | {'%', '$', '&', '+', ','}
+}
+
+/// What a link points to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatrixId {
+    User(UserId),
+    Room(RoomId),
+    RoomAlias(RoomAliasId),
+    /// An event in the room with the given ID or alias
+    Event(RoomOrAliasId, EventId),
+}
+
+impl From<UserId> for MatrixId {
+    fn from(user_id: UserId) -> Self {
+        MatrixId::User(user_id)
+    }
+}
+
+impl From<RoomId> for MatrixId {
+    fn from(room_id: RoomId) -> Self {
+        MatrixId::Room(room_id)
+    }
+}
+
+impl From<RoomAliasId> for MatrixId {
+    fn from(alias: RoomAliasId) -> Self {
+        MatrixId::RoomAlias(alias)
+    }
+}
+
+impl From<RoomOrAliasId> for MatrixId {
+    fn from(room: RoomOrAliasId) -> Self {
+        match room {
+            RoomOrAliasId::Id(room_id) => MatrixId::Room(room_id),
+            RoomOrAliasId::Alias(alias) => MatrixId::RoomAlias(alias),
+        }
+    }
+}
+
+/// A link to a user, room or event
+///
+/// Displayed as matrix.to link. Parsing accepts matrix.to links as well as
+/// `matrix:` URIs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permalink {
+    /// What the link points to
+    pub id: MatrixId,
+    /// Servers that can be asked to join the room
+    ///
+    /// Room IDs cannot be resolved on their own, so links to rooms and events
+    /// should name a few servers that are in the room.
+    pub via: Vec<ServerName>,
+}
+
+impl Permalink {
+    /// Creates a link without `via` servers
+    pub fn new<I: Into<MatrixId>>(id: I) -> Self {
+        Permalink {
+            id: id.into(),
+            via: Vec::new(),
+        }
+    }
+
+    /// Adds a server that can be asked to join the room
+    pub fn via(mut self, server_name: ServerName) -> Self {
+        if !self.via.contains(&server_name) {
+            self.via.push(server_name);
+        }
+        self
+    }
+
+    /// Parses a matrix.to link or a `matrix:` URI
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        if s.starts_with(MATRIX_TO_PREFIX) {
+            parse_matrix_to(s)
+        } else if s.starts_with(MATRIX_URI_PREFIX) {
+            parse_matrix_uri(s)
+        } else {
+            Err(invalid(
+                s,
+                "it is neither a matrix.to link nor a `matrix:` URI",
+            ))
+        }
+    }
+
+    /// Returns the link as matrix.to link, e. g.
+    /// `https://matrix.to/#/%23rust%3Amatrix.org`
+    pub fn matrix_to(&self) -> String {
+        let encode = |id: &str| utf8_percent_encode(id, MATRIX_TO_ENCODE_SET).to_string();
+        let path = match self.id {
+            MatrixId::User(ref user_id) => encode(user_id.as_str()),
+            MatrixId::Room(ref room_id) => encode(room_id.as_str()),
+            MatrixId::RoomAlias(ref alias) => encode(alias.as_str()),
+            MatrixId::Event(ref room, ref event_id) => {
+                format!("{}/{}", encode(room.as_str()), encode(event_id.as_str()))
+            }
+        };
+        format!("{}{}{}", MATRIX_TO_PREFIX, path, self.query())
+    }
+
+    /// Returns the link as `matrix:` URI, e. g. `matrix:r/rust:matrix.org`
+    pub fn matrix_uri(&self) -> String {
+        // The sigils are replaced by path segments
+        let encode = |id: &str| utf8_percent_encode(&id[1..], PATH_SEGMENT_ENCODE_SET).to_string();
+        let room_path = |room: &RoomOrAliasId| match *room {
+            RoomOrAliasId::Id(ref room_id) => format!("roomid/{}", encode(room_id.as_str())),
+            RoomOrAliasId::Alias(ref alias) => format!("r/{}", encode(alias.as_str())),
+        };
+        let path = match self.id {
+            MatrixId::User(ref user_id) => format!("u/{}", encode(user_id.as_str())),
+            MatrixId::Room(ref room_id) => format!("roomid/{}", encode(room_id.as_str())),
+            MatrixId::RoomAlias(ref alias) => format!("r/{}", encode(alias.as_str())),
+            MatrixId::Event(ref room, ref event_id) => {
+                format!("{}/e/{}", room_path(room), encode(event_id.as_str()))
+            }
+        };
+        format!("{}{}{}", MATRIX_URI_PREFIX, path, self.query())
+    }
+
+    /// Returns the `via` query parameters including the leading `?`, if any
+    fn query(&self) -> String {
+        let params: Vec<String> = self
+            .via
+            .iter()
+            .map(|server_name| format!("via={}", server_name))
+            .collect();
+        if params.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", params.join("&"))
+        }
+    }
+}
+
+impl FromStr for Permalink {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Permalink::parse(s)
+    }
+}
+
+impl fmt::Display for Permalink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.matrix_to())
+    }
+}
+
+/// Parses a matrix.to link, e. g.
+/// `https://matrix.to/#/!room%3Aexample.org/%24event?via=example.org`
+fn parse_matrix_to(link: &str) -> Result<Permalink, Error> {
+    let (path, query) = split_query(&link[MATRIX_TO_PREFIX.len()..]);
+    let mut segments = path.splitn(2, '/');
+    let first = decode(link, segments.next().unwrap())?;
+    let id = match segments.next() {
+        Some(event_id) => MatrixId::Event(
+            RoomOrAliasId::parse(&first)?,
+            EventId::parse(&decode(link, event_id)?)?,
+        ),
+        None if first.starts_with('@') => MatrixId::User(UserId::parse(&first)?),
+        None => RoomOrAliasId::parse(&first)?.into(),
+    };
+    Ok(Permalink {
+        id,
+        via: parse_via(link, query)?,
+    })
+}
+
+/// Parses a `matrix:` URI, e. g.
+/// `matrix:roomid/room:example.org/e/event?via=example.org`
+fn parse_matrix_uri(link: &str) -> Result<Permalink, Error> {
+    let (path, query) = split_query(&link[MATRIX_URI_PREFIX.len()..]);
+    let segments: Vec<&str> = path.split('/').collect();
+    // The sigils are replaced by path segments
+    let with_sigil = |sigil: char, segment: &str| -> Result<String, Error> {
+        Ok(format!("{}{}", sigil, decode(link, segment)?))
+    };
+    let room = |kind: &str, segment: &str| -> Result<RoomOrAliasId, Error> {
+        match kind {
+            "roomid" => Ok(RoomOrAliasId::Id(RoomId::parse(&with_sigil(
+                '!', segment,
+            )?)?)),
+            "r" => Ok(RoomOrAliasId::Alias(RoomAliasId::parse(&with_sigil(
+                '#', segment,
+            )?)?)),
+            _ => Err(invalid(link, "the kind of identifier is unknown")),
+        }
+    };
+
+    let id = match segments[..] {
+        ["u", user_id] => MatrixId::User(UserId::parse(&with_sigil('@', user_id)?)?),
+        [kind, room_id] => room(kind, room_id)?.into(),
+        [kind, room_id, "e", event_id] => MatrixId::Event(
+            room(kind, room_id)?,
+            EventId::parse(&with_sigil('$', event_id)?)?,
+        ),
+        _ => return Err(invalid(link, "the path is invalid")),
+    };
+    Ok(Permalink {
+        id,
+        via: parse_via(link, query)?,
+    })
+}
+
+/// Splits a path with query at the first `?`
+fn split_query(s: &str) -> (&str, &str) {
+    match s.find('?') {
+        Some(pos) => (&s[..pos], &s[pos + 1..]),
+        None => (s, ""),
+    }
+}
+
+/// Returns the values of all `via` parameters of a query
+///
+/// Other parameters, e. g. `action` of `matrix:` URIs, are ignored.
+fn parse_via(link: &str, query: &str) -> Result<Vec<ServerName>, Error> {
+    let mut via = Vec::new();
+    for param in query.split('&') {
+        if let Some(server_name) = param.strip_prefix("via=") {
+            via.push(ServerName::parse(&decode(link, server_name)?)?);
+        }
+    }
+    Ok(via)
+}
+
+fn decode(link: &str, s: &str) -> Result<String, Error> {
+    percent_decode(s.as_bytes())
+        .decode_utf8()
+        .map(|s| s.into_owned())
+        .map_err(|_| invalid(link, "it is not valid UTF-8"))
+}
+
+fn invalid(link: &str, reason: &str) -> Error {
+    Error::InvalidIdentifier(format!("`{}` is not a valid Matrix link: {}", link, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(server_name: &str) -> ServerName {
+        ServerName::parse(server_name).unwrap()
+    }
+
+    /// Checks that the link is generated as the given matrix.to link and
+    /// `matrix:` URI and that both are parsed back into the link
+    fn assert_round_trip(link: &Permalink, matrix_to: &str, matrix_uri: &str) {
+        assert_eq!(link.matrix_to(), matrix_to);
+        assert_eq!(link.matrix_uri(), matrix_uri);
+        assert_eq!(&Permalink::parse(matrix_to).unwrap(), link);
+        assert_eq!(&Permalink::parse(matrix_uri).unwrap(), link);
+    }
+
+    #[test]
+    fn user_links() {
+        let link = Permalink::new(UserId::parse("@bot:matrix.org").unwrap());
+        assert_round_trip(
+            &link,
+            "https://matrix.to/#/%40bot%3Amatrix.org",
+            "matrix:u/bot:matrix.org",
+        );
+        assert_eq!(
+            Permalink::parse("https://matrix.to/#/@bot:matrix.org").unwrap(),
+            link
+        );
+    }
+
+    #[test]
+    fn alias_links() {
+        let link = Permalink::new(RoomAliasId::parse("#rust:matrix.org").unwrap());
+        assert_round_trip(
+            &link,
+            "https://matrix.to/#/%23rust%3Amatrix.org",
+            "matrix:r/rust:matrix.org",
+        );
+    }
+
+    #[test]
+    fn room_links() {
+        let link = Permalink::new(RoomId::parse("!room:example.org").unwrap())
+            .via(server("example.org"))
+            .via(server("matrix.org:8448"));
+        assert_round_trip(
+            &link,
+            "https://matrix.to/#/!room%3Aexample.org?via=example.org&via=matrix.org:8448",
+            "matrix:roomid/room:example.org?via=example.org&via=matrix.org:8448",
+        );
+    }
+
+    #[test]
+    fn event_links() {
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        let event_id = EventId::parse("$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg").unwrap();
+        let link = Permalink::new(MatrixId::Event(room_id.into(), event_id.clone()))
+            .via(server("example.org"));
+        assert_round_trip(
+            &link,
+            "https://matrix.to/#/!room%3Aexample.org/%24Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg?via=example.org",
+            "matrix:roomid/room:example.org/e/Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg?via=example.org",
+        );
+
+        let alias = RoomAliasId::parse("#rust:matrix.org").unwrap();
+        let link = Permalink::new(MatrixId::Event(alias.into(), event_id));
+        assert_round_trip(
+            &link,
+            "https://matrix.to/#/%23rust%3Amatrix.org/%24Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg",
+            "matrix:r/rust:matrix.org/e/Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg",
+        );
+    }
+
+    #[test]
+    fn via_servers_are_not_repeated() {
+        let link = Permalink::new(RoomId::parse("!room:example.org").unwrap())
+            .via(server("example.org"))
+            .via(server("example.org"));
+        assert_eq!(link.via, vec![server("example.org")]);
+    }
+
+    #[test]
+    fn other_query_parameters_are_ignored() {
+        let link = Permalink::parse("matrix:r/rust:matrix.org?action=join&via=matrix.org").unwrap();
+        assert_eq!(
+            link,
+            Permalink::new(RoomAliasId::parse("#rust:matrix.org").unwrap())
+                .via(server("matrix.org"))
+        );
+    }
+
+    #[test]
+    fn invalid_links() {
+        for link in &[
+            "https://example.org/#/@bot:matrix.org",
+            "#rust:matrix.org",
+            "https://matrix.to/#/",
+            "https://matrix.to/#/rust:matrix.org",
+            "https://matrix.to/#/%23rust%3Amatrix.org?via=https://matrix.org",
+            "https://matrix.to/#/%23rust%3Amatrix.org/not-an-event",
+            "matrix:",
+            "matrix:x/rust:matrix.org",
+            "matrix:u/:matrix.org",
+            "matrix:r/rust:matrix.org/e",
+            "matrix:u/bot:matrix.org/e/event",
+        ] {
+            assert!(Permalink::parse(link).is_err(), "{} was accepted", link);
+        }
+    }
+}
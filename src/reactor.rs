@@ -0,0 +1,96 @@
+//! The reactor running the requests of the blocking API
+//!
+//! `Homeserver`, `Room` and the requests of the `HomeserverBuilder` use the
+//! same implementation as the non-blocking API: Every blocking method passes
+//! its operation to a background thread running a `tokio_core` reactor and
+//! waits for the result. The thread is shared by a builder, the homeserver
+//! object created from it and all its rooms, and ends once all of them have
+//! been dropped.
+
+use std::sync::mpsc;
+use std::thread;
+
+use futures::sync::{mpsc as channel, oneshot};
+use futures::{Future, Stream};
+use reqwest::unstable::async;
+use tokio_core::reactor::{Core, Handle};
+
+use error::Error;
+use http::HttpConfig;
+use AsyncResult;
+
+/// An operation to start on the reactor thread
+type Job = Box<dyn FnOnce(&async::Client, &Handle) + Send>;
+
+/// A handle to the reactor thread
+pub(crate) struct Reactor {
+    jobs: channel::UnboundedSender<Job>,
+}
+
+impl Reactor {
+    /// Starts the reactor thread with a client built from `http`
+    ///
+    /// # Panics
+    /// If the reactor or the TLS backend cannot be initialized, this function
+    /// panics.
+    pub fn start(http: HttpConfig) -> Self {
+        let (jobs, received) = channel::unbounded::<Job>();
+        let (started, start_result) = mpsc::channel();
+        thread::Builder::new()
+            .name("dementia-reactor".to_owned())
+            .spawn(move || {
+                let mut core = Core::new().expect("Starting the reactor failed");
+                let handle = core.handle();
+                let client = http.async_client(&handle);
+                let _ = started.send(());
+                // Ends once all senders, i. e. all `Reactor`s, are dropped
+                let _ = core.run(received.for_each(|job| {
+                    job(&client, &handle);
+                    Ok(())
+                }));
+            })
+            .expect("Starting the reactor thread failed");
+        // Report a failure to build the client on the calling thread
+        start_result.recv().expect("Starting the reactor failed");
+        Reactor { jobs }
+    }
+
+    /// Runs the future returned by `operation` on the reactor and waits for
+    /// its result
+    ///
+    /// Must not be called on the reactor thread itself, e. g. from the
+    /// callback set with `HomeserverBuilder::on_session_refresh`, since the
+    /// reactor would wait for itself.
+    pub fn run<T, F>(&self, operation: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&async::Client, &Handle) -> AsyncResult<T> + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        let job: Job = Box::new(move |client, handle| {
+            handle.spawn(operation(client, handle).then(move |res| {
+                let _ = done.send(res);
+                Ok(())
+            }));
+        });
+        self.jobs
+            .unbounded_send(job)
+            .expect("The reactor has stopped");
+        result.wait().expect("The reactor has stopped")
+    }
+}
+
+/// Runs a blocking operation, e. g. on the state store, on its own thread, so
+/// that it does not hold up the reactor
+pub(crate) fn run_blocking<T, F>(operation: F) -> AsyncResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    let (done, result) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = done.send(operation());
+    });
+    // The sender is only dropped without a result if the operation panicked
+    Box::new(result.then(|res| res.expect("The blocking operation panicked")))
+}
@@ -0,0 +1,408 @@
+//! Requests to the endpoints of the client-server API
+
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future};
+use reqwest::unstable::async;
+use tokio_core::reactor::Handle;
+
+use reqwest;
+use reqwest::header::{Authorization, Bearer};
+use serde::de::DeserializeOwned;
+use serde::{ser, Serialize};
+use serde_json::{self, Value};
+use url::form_urlencoded;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use error::{parse_response, Error};
+use retry::{retry, RetryPolicy};
+use AsyncResult;
+
+/// The version path segment of the client-server API, `v3` or `r0`
+///
+/// It is shared between all objects talking to the same homeserver. During
+/// discovery, it is set to the newest version the homeserver reports in
+/// `/versions`.
+#[derive(Clone, Debug)]
+pub(crate) struct ApiVersion(Arc<Mutex<&'static str>>);
+
+impl ApiVersion {
+    pub fn new(version: &'static str) -> Self {
+        ApiVersion(Arc::new(Mutex::new(version)))
+    }
+
+    pub fn get(&self) -> &'static str {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn set(&self, version: &'static str) {
+        *self.0.lock().unwrap() = version;
+    }
+}
+
+/// How a request is authenticated
+#[derive(Clone)]
+pub(crate) enum Auth {
+    /// The request is not authenticated
+    None,
+    /// The access token is sent in the `Authorization` header
+    Header(String),
+    /// The access token is sent in the query string
+    ///
+    /// This leaks the access token into the logs of proxies and servers and
+    /// should only be used for legacy servers.
+    Query(String),
+}
+
+/// A request to an endpoint of the client-server API
+///
+/// The URL is built from the homeserver URL, the API version and the path
+/// segments, e. g. `["rooms", room_id, "invite"]` for
+/// `/_matrix/client/v3/rooms/{roomId}/invite`.
+/// The path segments and query parameters are encoded when the URL is built.
+///
+/// `POST` requests are not idempotent by default, so they are not sent again
+/// after server errors or connection failures, see `retry`.
+#[derive(Clone)]
+pub(crate) struct Request {
+    method: reqwest::Method,
+    path: Vec<String>,
+    query: Vec<(String, String)>,
+    /// The JSON body, or the error serializing it, which is returned once
+    /// the request is sent
+    body: Result<Option<Value>, Arc<serde_json::Error>>,
+    versioned: bool,
+    idempotent: bool,
+}
+
+impl Request {
+    pub fn new(method: reqwest::Method, path: &[&str]) -> Self {
+        Request {
+            idempotent: method != reqwest::Method::Post,
+            method,
+            path: path.iter().map(|segment| segment.to_string()).collect(),
+            query: Vec::new(),
+            body: Ok(None),
+            versioned: true,
+        }
+    }
+
+    pub fn get(path: &[&str]) -> Self {
+        Self::new(reqwest::Method::Get, path)
+    }
+
+    pub fn post(path: &[&str]) -> Self {
+        Self::new(reqwest::Method::Post, path)
+    }
+
+    pub fn put(path: &[&str]) -> Self {
+        Self::new(reqwest::Method::Put, path)
+    }
+
+    pub fn delete(path: &[&str]) -> Self {
+        Self::new(reqwest::Method::Delete, path)
+    }
+
+    /// Request an endpoint that is not part of a specific API version, e. g.
+    /// `/_matrix/client/versions`
+    pub fn unversioned(mut self) -> Self {
+        self.versioned = false;
+        self
+    }
+
+    /// Set whether sending the request twice has the same effect as sending
+    /// it once, e. g. joining a room
+    pub fn idempotent(mut self, idempotent: bool) -> Self {
+        self.idempotent = idempotent;
+        self
+    }
+
+    /// Add a query parameter
+    pub fn query(mut self, key: &str, value: &str) -> Self {
+        self.query.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Set the JSON body
+    ///
+    /// If it cannot be serialized, e. g. because a map has non-string keys,
+    /// sending the request fails with `Error::Json`.
+    pub fn json<T: Serialize + ?Sized>(mut self, body: &T) -> Self {
+        self.body = serde_json::to_value(body).map(Some).map_err(Arc::new);
+        self
+    }
+
+    /// Returns the JSON body
+    #[cfg(test)]
+    pub fn body(&self) -> Option<&Value> {
+        self.body.as_ref().ok().and_then(Option::as_ref)
+    }
+
+    /// Returns the JSON body, or the error serializing it
+    fn checked_body(&self) -> Result<Option<&Value>, Error> {
+        match self.body {
+            Ok(ref body) => Ok(body.as_ref()),
+            // Serialization errors consist of their message only, so the copy
+            // is identical
+            Err(ref error) => Err(Error::Json(ser::Error::custom(error))),
+        }
+    }
+
+    /// Returns the URL of the request
+    pub fn url(&self, server: &str, api_version: &str) -> String {
+        self.url_with_query(server, api_version, &self.query)
+    }
+
+    fn url_with_query(
+        &self,
+        server: &str,
+        api_version: &str,
+        query: &[(String, String)],
+    ) -> String {
+        let mut url = format!("{}/_matrix/client", server);
+        if self.versioned {
+            url.push('/');
+            url.push_str(api_version);
+        }
+        for segment in &self.path {
+            url.push('/');
+            url.extend(utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET));
+        }
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(
+                &form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(query)
+                    .finish(),
+            );
+        }
+        url
+    }
+
+    /// Returns the URL of the request including the access token, if it is
+    /// sent in the query string
+    fn authenticated_url(&self, server: &str, api_version: &str, auth: &Auth) -> String {
+        match *auth {
+            Auth::Query(ref access_token) => {
+                let mut query = self.query.clone();
+                query.push(("access_token".to_owned(), access_token.to_owned()));
+                self.url_with_query(server, api_version, &query)
+            }
+            _ => self.url(server, api_version),
+        }
+    }
+}
+
+/// A request together with how its response is turned into the result
+///
+/// The response body is read as `R`, see `ServerInfo::call`.
+pub(crate) struct Call<R, T> {
+    pub request: Request,
+    pub response: ReadResponse<R, T>,
+}
+
+/// Turns the response body, or the error of the request, into the result
+pub(crate) type ReadResponse<R, T> = Box<dyn FnOnce(Result<R, Error>) -> Result<T, Error>>;
+
+impl<R: 'static> Call<R, R> {
+    /// A call returning the response body as it is
+    pub fn new(request: Request) -> Self {
+        Call {
+            request,
+            response: Box::new(|res| res),
+        }
+    }
+}
+
+impl<R: 'static, T: 'static> Call<R, T> {
+    /// Turns the result of the call into a different one with `f`
+    pub fn then<U, F>(self, f: F) -> Call<R, U>
+    where
+        F: FnOnce(Result<T, Error>) -> Result<U, Error> + 'static,
+    {
+        let response = self.response;
+        Call {
+            request: self.request,
+            response: Box::new(move |res| f(response(res))),
+        }
+    }
+
+    pub fn map<U, F>(self, f: F) -> Call<R, U>
+    where
+        F: FnOnce(T) -> U + 'static,
+    {
+        self.then(|res| res.map(f))
+    }
+
+    pub fn map_err<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Error) -> Error + 'static,
+    {
+        self.then(|res| res.map_err(f))
+    }
+}
+
+impl Request {
+    /// Sends the request once
+    pub fn send(
+        &self,
+        client: &async::Client,
+        server: &str,
+        api_version: &str,
+        auth: &Auth,
+    ) -> AsyncResult<async::Response> {
+        let body = match self.checked_body() {
+            Ok(body) => body,
+            Err(error) => return Box::new(future::err(error)),
+        };
+        let url = self.authenticated_url(server, api_version, auth);
+        let mut builder = client.request(self.method.clone(), &url);
+        if let Auth::Header(ref access_token) = *auth {
+            builder.header(Authorization(Bearer {
+                token: access_token.to_owned(),
+            }));
+        }
+        if let Some(body) = body {
+            builder.json(body);
+        }
+        Box::new(builder.send().from_err())
+    }
+
+    /// Sends the request and reads its response
+    ///
+    /// Transient failures are retried according to `retry_policy`, server
+    /// errors and connection failures only if the request is idempotent.
+    /// If the homeserver does not know the endpoint in API version `v3`, the
+    /// request is sent again using `r0`, see `with_fallback`.
+    pub fn execute<T: DeserializeOwned + 'static>(
+        self,
+        client: &async::Client,
+        handle: &Handle,
+        server: &str,
+        api_version: &ApiVersion,
+        auth: Auth,
+        retry_policy: &RetryPolicy,
+    ) -> AsyncResult<T> {
+        let versioned = self.versioned;
+        let idempotent = self.idempotent;
+        let request = Rc::new(self);
+        let client = client.clone();
+        let handle = handle.clone();
+        let server = server.to_owned();
+        let retry_policy = *retry_policy;
+        let send = move |version: &'static str| {
+            let request = request.clone();
+            let client = client.clone();
+            let server = server.clone();
+            let auth = auth.clone();
+            retry(
+                retry_policy,
+                idempotent,
+                &handle,
+                move || -> AsyncResult<T> {
+                    Box::new(
+                        request
+                            .send(&client, &server, version, &auth)
+                            .and_then(parse_response),
+                    )
+                },
+            )
+        };
+        if !versioned {
+            return send(api_version.get());
+        }
+        with_fallback(api_version, send)
+    }
+}
+
+/// Calls `send` with the API version to use, and once more with `r0` if the
+/// homeserver does not know the endpoint in `v3`
+///
+/// The fallback only applies to this request. A homeserver may support `v3`
+/// for most endpoints and lack only a few, so `api_version` is left as it is.
+pub(crate) fn with_fallback<T, F>(api_version: &ApiVersion, send: F) -> AsyncResult<T>
+where
+    T: 'static,
+    F: Fn(&'static str) -> AsyncResult<T> + 'static,
+{
+    let version = api_version.get();
+    Box::new(send(version).then(move |res| -> AsyncResult<T> {
+        if version != "v3" || !is_unrecognized(&res) {
+            return Box::new(future::result(res));
+        }
+
+        send("r0")
+    }))
+}
+
+fn is_unrecognized<T>(res: &Result<T, Error>) -> bool {
+    match *res {
+        Err(ref error) => error.errcode() == Some("M_UNRECOGNIZED"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::MatrixError;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    fn unrecognized() -> Error {
+        Error::Matrix(MatrixError {
+            status: 404,
+            errcode: "M_UNRECOGNIZED".to_owned(),
+            error: String::new(),
+            soft_logout: false,
+            retry_after_ms: None,
+        })
+    }
+
+    #[test]
+    fn invalid_body() {
+        let mut body = HashMap::new();
+        body.insert((1, 2), "value");
+        let request = Request::post(&["rooms"]).json(&body);
+
+        match request.checked_body() {
+            Err(Error::Json(_)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn fallback_to_r0() {
+        let api_version = ApiVersion::new("v3");
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let log = sent.clone();
+        let res = with_fallback(&api_version, move |version| -> AsyncResult<_> {
+            log.borrow_mut().push(version);
+            match version {
+                "v3" => Box::new(future::err(unrecognized())),
+                _ => Box::new(future::ok(version)),
+            }
+        });
+
+        assert_eq!(res.wait().unwrap(), "r0");
+        assert_eq!(*sent.borrow(), vec!["v3", "r0"]);
+        // Other endpoints may well be supported in `v3`
+        assert_eq!(api_version.get(), "v3");
+    }
+
+    #[test]
+    fn no_fallback_from_r0() {
+        let api_version = ApiVersion::new("r0");
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let log = sent.clone();
+        let res = with_fallback(&api_version, move |version| -> AsyncResult<()> {
+            log.borrow_mut().push(version);
+            Box::new(future::err(unrecognized()))
+        });
+
+        assert_eq!(res.wait().unwrap_err().errcode(), Some("M_UNRECOGNIZED"));
+        assert_eq!(*sent.borrow(), vec!["r0"]);
+    }
+}
@@ -0,0 +1,271 @@
+//! Retrying requests that failed for transient reasons
+//!
+//! A request is retried if the homeserver rate limits it (status code 429 or
+//! `M_LIMIT_EXCEEDED`).
+//! Idempotent requests are also retried if the homeserver fails with a status
+//! code of 500 or above, or if the connection fails. Other requests may have
+//! taken effect in that case, e. g. a room may have been created, so they are
+//! not sent again.
+
+use std::io;
+use std::time::Duration;
+
+use futures::future::{self, Loop};
+use futures::{Future, IntoFuture};
+use reqwest;
+use tokio_core::reactor::{Handle, Timeout};
+
+use error::Error;
+use AsyncResult;
+
+/// Controls how often and after which delay failed requests are retried
+///
+/// The delay before the first retry is `initial_backoff`. It is multiplied
+/// by `backoff_factor` for every further retry, but never exceeds
+/// `max_backoff`.
+/// If the homeserver rate limits a request and says how long to wait, that
+/// delay is used instead, again limited to `max_backoff`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// The maximum number of retries of a single request
+    pub max_retries: u32,
+    /// The delay before the first retry
+    pub initial_backoff: Duration,
+    /// The maximum delay between two retries
+    pub max_backoff: Duration,
+    /// The factor the delay is multiplied with after every retry
+    pub backoff_factor: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            backoff_factor: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries failed requests
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Returns the delay before the given retry, starting at 0
+    fn backoff(&self, retry: u32) -> Duration {
+        self.backoff_factor
+            .checked_pow(retry)
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// Calls `send` until it succeeds, fails permanently or the retries of
+/// `policy` are exhausted
+///
+/// Server errors and connection failures are only retried if the request is
+/// `idempotent`.
+/// If the last retry fails, `Error::RetriesExhausted` is returned.
+/// The delays between the retries are timers on the reactor of `handle`.
+pub(crate) fn retry<T, F>(
+    policy: RetryPolicy,
+    idempotent: bool,
+    handle: &Handle,
+    send: F,
+) -> AsyncResult<T>
+where
+    T: 'static,
+    F: Fn() -> AsyncResult<T> + 'static,
+{
+    let handle = handle.clone();
+    Box::new(future::loop_fn(0, move |retries| {
+        let handle = handle.clone();
+        send().then(move |res| -> AsyncResult<Loop<T, u32>> {
+            let error = match res {
+                Ok(value) => return Box::new(future::ok(Loop::Break(value))),
+                Err(error) => error,
+            };
+            let delay = match next_retry(&policy, idempotent, retries, error) {
+                Ok(delay) => delay,
+                Err(error) => return Box::new(future::err(error)),
+            };
+            // If the timer cannot be created, retry right away
+            let timeout = Timeout::new(delay, &handle)
+                .into_future()
+                .flatten()
+                .then(move |_| Ok(Loop::Continue(retries + 1)));
+            Box::new(timeout)
+        })
+    }))
+}
+
+/// Returns how long to wait before the next retry after `error`, or the error
+/// to return if the request must not be retried
+fn next_retry(
+    policy: &RetryPolicy,
+    idempotent: bool,
+    retries: u32,
+    error: Error,
+) -> Result<Duration, Error> {
+    let delay = match delay(policy, idempotent, retries, &error) {
+        Some(delay) => delay,
+        None => return Err(error),
+    };
+    if retries == policy.max_retries {
+        return Err(Error::RetriesExhausted {
+            attempts: retries + 1,
+            error: Box::new(error),
+        });
+    }
+    Ok(delay)
+}
+
+/// Returns how long to wait before retrying after `error`, or `None` if the
+/// request must not be retried
+fn delay(policy: &RetryPolicy, idempotent: bool, retries: u32, error: &Error) -> Option<Duration> {
+    match *error {
+        Error::Matrix(ref error) if error.status == 429 || error.errcode == "M_LIMIT_EXCEEDED" => {
+            // Do not let the homeserver stall the client arbitrarily long
+            Some(error.retry_after_ms.map_or(policy.backoff(retries), |ms| {
+                Duration::from_millis(ms).min(policy.max_backoff)
+            }))
+        }
+        Error::Matrix(ref error) if idempotent && error.status >= 500 => {
+            Some(policy.backoff(retries))
+        }
+        Error::Http(ref error) if idempotent && is_transient(error) => {
+            Some(policy.backoff(retries))
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether the connection failed, as opposed to e. g. an invalid URL
+/// or response body
+fn is_transient(error: &reqwest::Error) -> bool {
+    error.is_http()
+        || error
+            .get_ref()
+            .and_then(|error| error.downcast_ref::<io::Error>())
+            .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::MatrixError;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use tokio_core::reactor::Core;
+
+    fn matrix_error(status: u16, errcode: &str, retry_after_ms: Option<u64>) -> Error {
+        Error::Matrix(MatrixError {
+            status,
+            errcode: errcode.to_owned(),
+            error: String::new(),
+            soft_logout: false,
+            retry_after_ms,
+        })
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            backoff_factor: 3,
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = policy();
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(300));
+        assert_eq!(policy.backoff(2), Duration::from_millis(900));
+        assert_eq!(policy.backoff(3), Duration::from_secs(1));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn rate_limited() {
+        let policy = policy();
+        let error = matrix_error(429, "M_LIMIT_EXCEEDED", None);
+        assert_eq!(
+            delay(&policy, false, 1, &error),
+            Some(Duration::from_millis(300))
+        );
+
+        let error = matrix_error(429, "M_LIMIT_EXCEEDED", Some(250));
+        assert_eq!(
+            delay(&policy, false, 1, &error),
+            Some(Duration::from_millis(250))
+        );
+
+        let error = matrix_error(429, "M_LIMIT_EXCEEDED", Some(u64::MAX));
+        assert_eq!(
+            delay(&policy, true, 0, &error),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn server_errors() {
+        let policy = policy();
+        let error = matrix_error(502, "M_UNKNOWN", None);
+        // e. g. `POST /createRoom`
+        assert_eq!(delay(&policy, false, 0, &error), None);
+        // e. g. `PUT /rooms/{roomId}/send/{eventType}/{txnId}`
+        assert_eq!(
+            delay(&policy, true, 0, &error),
+            Some(Duration::from_millis(100))
+        );
+
+        let error = matrix_error(403, "M_FORBIDDEN", None);
+        assert_eq!(delay(&policy, true, 0, &error), None);
+    }
+
+    #[test]
+    fn retries_exhausted() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(0),
+            ..policy()
+        };
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let counter = attempts.clone();
+        let res: Result<(), Error> = core.run(retry(policy, true, &core.handle(), move || {
+            counter.set(counter.get() + 1);
+            Box::new(future::err(matrix_error(503, "M_UNKNOWN", None)))
+        }));
+
+        assert_eq!(attempts.get(), 4);
+        match res {
+            Err(Error::RetriesExhausted { attempts, error }) => {
+                assert_eq!(attempts, 4);
+                assert_eq!(error.errcode(), Some("M_UNKNOWN"));
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn not_retried() {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let counter = attempts.clone();
+        let res: Result<(), Error> = core.run(retry(policy(), false, &core.handle(), move || {
+            counter.set(counter.get() + 1);
+            Box::new(future::err(matrix_error(500, "M_UNKNOWN", None)))
+        }));
+
+        assert_eq!(attempts.get(), 1);
+        assert_eq!(res.unwrap_err().errcode(), Some("M_UNKNOWN"));
+    }
+}
@@ -0,0 +1,177 @@
+//! Persistent client state
+//!
+//! State that has to survive a restart of the bot, like the outbox of a
+//! room, is kept in a `StateStore`. Each `Homeserver` has its own store,
+//! which is cleared on logout.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde_json;
+
+/// A key-value store for client state
+///
+/// Keys are chosen by the library, e. g. `outbox/!room:example.org`.
+/// Values are JSON documents.
+/// The store is shared between threads, so it has to synchronize access
+/// itself.
+pub trait StateStore: Send + Sync {
+    /// Returns the value stored for `key`, if any
+    fn get(&self, key: &str) -> io::Result<Option<String>>;
+    /// Stores `value` for `key`, replacing any previous value
+    fn set(&self, key: &str, value: &str) -> io::Result<()>;
+    /// Removes the value stored for `key`
+    fn remove(&self, key: &str) -> io::Result<()>;
+    /// Removes all values
+    fn clear(&self) -> io::Result<()>;
+}
+
+/// A state store that keeps the state in memory only
+///
+/// This is the default. The state is lost when the program exits.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    values: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn get(&self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &str) -> io::Result<()> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        self.values.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// A state store that keeps the state in a JSON file
+///
+/// The whole file is rewritten on every change, so this is meant for small
+/// amounts of state, like a few queued messages.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    values: Mutex<HashMap<String, String>>,
+}
+
+impl FileStore {
+    /// Opens the store in the given file
+    ///
+    /// The file is created on the first change if it does not exist yet.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let values = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file)?,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(error) => return Err(error),
+        };
+        Ok(FileStore {
+            path,
+            values: Mutex::new(values),
+        })
+    }
+
+    /// Applies `change` to the values and writes them to the file
+    ///
+    /// The change is only kept in memory once it has been written, so that
+    /// memory and file do not diverge if writing fails.
+    fn update<F: FnOnce(&mut HashMap<String, String>)>(&self, change: F) -> io::Result<()> {
+        let mut values = self.values.lock().unwrap();
+        let mut changed = values.clone();
+        change(&mut changed);
+        self.write(&changed)?;
+        *values = changed;
+        Ok(())
+    }
+
+    /// Writes all values to a temporary file and moves it over the store file,
+    /// so that the store file is never left half-written
+    ///
+    /// The caller holds the lock of `values`, so that only one thread writes
+    /// at a time.
+    fn write(&self, values: &HashMap<String, String>) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let file = File::create(&tmp_path)?;
+        serde_json::to_writer(&file, values)?;
+        // Otherwise the rename may reach the disk before the contents do
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)
+    }
+}
+
+impl StateStore for FileStore {
+    fn get(&self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &str) -> io::Result<()> {
+        self.update(|values| {
+            values.insert(key.to_owned(), value.to_owned());
+        })
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        self.update(|values| {
+            values.remove(key);
+        })
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        self.update(HashMap::clear)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn failed_write_is_not_kept() {
+        let path = env::temp_dir()
+            .join("dementia-missing-dir")
+            .join("state.json");
+        let store = FileStore::open(path).unwrap();
+
+        assert!(store.set("key", "value").is_err());
+        assert_eq!(store.get("key").unwrap(), None);
+    }
+
+    #[test]
+    fn values_are_written() {
+        let path = env::temp_dir().join(format!("dementia-store-{}.json", process::id()));
+        let store = FileStore::open(&path).unwrap();
+        store.set("key", "value").unwrap();
+        store.set("other", "value").unwrap();
+        store.remove("other").unwrap();
+
+        let reopened = FileStore::open(&path).unwrap();
+        assert_eq!(reopened.get("key").unwrap(), Some("value".to_owned()));
+        assert_eq!(reopened.get("other").unwrap(), None);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! User-interactive authentication
+//!
+//! Some endpoints, like registration, require the client to complete one of
+//! several flows of authentication stages, e. g. entering a password or
+//! accepting the terms of service.
+//! The homeserver answers such requests with status code 401 and a
+//! description of the flows until one of them has been completed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::future::{self, Loop};
+use futures::Future;
+use reqwest::unstable::async;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use error::{matrix_error, parse_response, read_body, Error};
+use {AsyncResult, UserIdentifier, REDACTED};
+
+/// A sequence of authentication stages that completes the authentication
+#[derive(Deserialize, Debug, Clone)]
+pub struct UiaFlow {
+    /// The stage types, e. g. `m.login.password` or `m.login.dummy`
+    pub stages: Vec<String>,
+}
+
+/// The state of an ongoing user-interactive authentication as reported by
+/// the homeserver
+#[derive(Deserialize, Debug, Clone)]
+pub struct UiaInfo {
+    /// The flows that complete the authentication
+    #[serde(default)]
+    pub flows: Vec<UiaFlow>,
+    /// Additional information for the stages, by stage type
+    #[serde(default)]
+    pub params: HashMap<String, Value>,
+    /// The session ID, which has to be sent with every stage
+    pub session: Option<String>,
+    /// The stages that have already been completed
+    #[serde(default)]
+    pub completed: Vec<String>,
+    /// The error code, if the last stage failed
+    pub errcode: Option<String>,
+    /// The error message, if the last stage failed
+    pub error: Option<String>,
+}
+
+/// The credentials used to complete the stages of user-interactive
+/// authentication
+///
+/// The stage `m.login.dummy` can always be completed.
+/// For all other supported stages, the corresponding credentials have to be
+/// set.
+/// Stages needing interaction with a human, like `m.login.recaptcha` and
+/// `m.login.terms`, are passed through: If the homeserver requires them,
+/// `Error::Uia` is returned, containing the parameters of the stages, e. g.
+/// the reCAPTCHA public key or the terms of service to present.
+/// After the user has solved the captcha or accepted the terms, the request
+/// can be repeated with `recaptcha_response` or `accept_terms` set.
+#[derive(Default, Clone)]
+pub struct UiaCredentials {
+    /// The user and password for `m.login.password`
+    pub password: Option<(UserIdentifier, String)>,
+    /// The token for `m.login.registration_token`
+    pub registration_token: Option<String>,
+    /// The reCAPTCHA response for `m.login.recaptcha`
+    pub recaptcha_response: Option<String>,
+    /// Whether the user accepted the terms of service for `m.login.terms`
+    pub accept_terms: bool,
+}
+
+impl fmt::Debug for UiaCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let password = self
+            .password
+            .as_ref()
+            .map(|(identifier, _)| (identifier, REDACTED));
+        let registration_token = self.registration_token.as_ref().map(|_| REDACTED);
+        f.debug_struct("UiaCredentials")
+            .field("password", &password)
+            .field("registration_token", &registration_token)
+            .field("recaptcha_response", &self.recaptcha_response)
+            .field("accept_terms", &self.accept_terms)
+            .finish()
+    }
+}
+
+impl UiaCredentials {
+    /// Returns whether the given stage can be completed with these credentials
+    pub fn supports(&self, stage: &str) -> bool {
+        match stage {
+            "m.login.dummy" => true,
+            "m.login.password" => self.password.is_some(),
+            "m.login.registration_token" => self.registration_token.is_some(),
+            "m.login.recaptcha" => self.recaptcha_response.is_some(),
+            "m.login.terms" => self.accept_terms,
+            _ => false,
+        }
+    }
+
+    /// Returns the authentication data for the given stage
+    fn auth_data(&self, stage: &str, session: Option<&String>) -> Value {
+        let mut auth = json!({ "type": stage });
+        if let Some(session) = session {
+            auth["session"] = json!(session);
+        }
+        match stage {
+            "m.login.password" => {
+                if let Some((ref identifier, ref password)) = self.password {
+                    auth["identifier"] = json!(identifier);
+                    auth["password"] = json!(password);
+                }
+            }
+            "m.login.registration_token" => {
+                auth["token"] = json!(self.registration_token);
+            }
+            "m.login.recaptcha" => {
+                auth["response"] = json!(self.recaptcha_response);
+            }
+            _ => (),
+        }
+        auth
+    }
+}
+
+/// Sends a request protected by user-interactive authentication
+///
+/// `send` is called with the authentication data to include in the request
+/// body as `auth`, which is `None` for the first attempt.
+/// As long as the homeserver asks for further stages, the first flow that
+/// can be completed with `credentials` is followed.
+pub(crate) fn authenticate<T, F>(credentials: UiaCredentials, send: F) -> AsyncResult<T>
+where
+    T: DeserializeOwned + 'static,
+    F: Fn(Option<&Value>) -> AsyncResult<async::Response> + 'static,
+{
+    let credentials = Rc::new(credentials);
+    Box::new(future::loop_fn(
+        (None, None),
+        move |(auth, last_stage): (Option<Value>, Option<String>)| {
+            let credentials = credentials.clone();
+            send(auth.as_ref()).and_then(move |res| -> AsyncResult<Loop<T, _>> {
+                if res.status().as_u16() != 401 {
+                    return Box::new(parse_response(res).map(Loop::Break));
+                }
+
+                Box::new(read_body(res).and_then(move |body| {
+                    let (auth, stage) = next_stage(&credentials, body, last_stage)?;
+                    Ok(Loop::Continue((Some(auth), Some(stage))))
+                }))
+            })
+        },
+    ))
+}
+
+/// Reads the body of a 401 response and returns the authentication data for
+/// the next stage together with its type
+fn next_stage(
+    credentials: &UiaCredentials,
+    body: String,
+    last_stage: Option<String>,
+) -> Result<(Value, String), Error> {
+    let info: UiaInfo = match serde_json::from_str::<UiaInfo>(&body) {
+        Ok(ref info) if !info.flows.is_empty() => info.clone(),
+        _ => return Err(matrix_error(401, body)),
+    };
+
+    // The stage submitted last has been rejected, e. g. a wrong password
+    if info.errcode.is_some() {
+        if let Some(ref stage) = last_stage {
+            if !info.completed.contains(stage) {
+                return Err(matrix_error(401, body));
+            }
+        }
+    }
+
+    let stage = info
+        .flows
+        .iter()
+        .filter(|flow| {
+            flow.stages
+                .iter()
+                .all(|stage| info.completed.contains(stage) || credentials.supports(stage))
+        })
+        .filter_map(|flow| {
+            flow.stages
+                .iter()
+                .find(|stage| !info.completed.contains(stage))
+        })
+        .next()
+        .cloned();
+
+    match stage {
+        Some(stage) if last_stage.as_ref() != Some(&stage) => {
+            let auth = credentials.auth_data(&stage, info.session.as_ref());
+            Ok((auth, stage))
+        }
+        _ => Err(Error::Uia(Box::new(info))),
+    }
+}